@@ -0,0 +1,555 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+//! The .spdc binary format. All integers are little endian.
+//!
+//! ```text
+//! magic     b"SPDC"
+//! version   u16
+//! flags     u16, bit 0 set if a debug section follows the code and bit 1
+//!           if jump targets do
+//! constants u32 count then that many i64, the immediates of every Push
+//!           and Halt
+//! code      u32 count then that many instructions, each an opcode byte
+//!           followed by its operands as u32s, Push refers to its
+//!           immediate by index into the constants and jumps have a byte
+//!           that is 1 when a u32 address follows and 0 when they pop it,
+//!           Halt likewise has a byte that is 1 when its code follows as
+//!           an index into the constants
+//! targets   u32 count then that many u32 instruction indexes that jumps
+//!           popping their destination may land on
+//! debug     u32 count then that many source files, each a name followed by
+//!           a u32 count of lines and the lines, the file index and source
+//!           line of every instruction, then the label and variable tables
+//!           and a u32 count of function frames, each the u32 index of its
+//!           Enter and of its end followed by a u32 count of slot names
+//!           and the names
+//! ```
+//!
+//! Strings are a u32 byte length followed by UTF-8 and tables are a u32
+//! count followed by (string, u32) pairs.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+
+use crate::types::{
+    DebugInfo, ExecError, FrameLayout, Instruction, Instruction::*, Program, SourceFile, SourceMap,
+    Target,
+};
+
+pub const MAGIC: &[u8; 4] = b"SPDC";
+pub const VERSION: u16 = 2;
+const HAS_DEBUG: u16 = 1;
+const HAS_JUMP_TARGETS: u16 = 2;
+
+pub fn encode(program: &Program) -> Result<Vec<u8>, ExecError> {
+    let mut constants: Vec<i64> = Vec::new();
+    let mut constant_idxs: HashMap<i64, usize> = HashMap::new();
+    let mut constant = |val: i64| {
+        *constant_idxs.entry(val).or_insert_with(|| {
+            constants.push(val);
+            constants.len() - 1
+        })
+    };
+    let mut code = Encoder::default();
+    code.len(program.instructions.len())?;
+    for instruction in program.instructions.iter() {
+        code.u8(opcode(instruction));
+        match instruction {
+            Push(val) => code.len(constant(*val))?,
+            Halt(Some(val)) => {
+                code.u8(1);
+                code.len(constant(*val))?;
+            }
+            Halt(None) => code.u8(0),
+            Call(addr) | LoadLocal(addr) | StoreLocal(addr) => code.len(*addr)?,
+            Enter(params, size) => {
+                code.len(*params)?;
+                code.len(*size)?;
+            }
+            _ => (),
+        }
+        match instruction.jump_target() {
+            Some(Target::Addr(addr)) => {
+                code.u8(1);
+                code.len(addr)?;
+            }
+            Some(Target::Stack) => code.u8(0),
+            None => (),
+        }
+    }
+
+    let mut out = Encoder::default();
+    out.bytes.extend_from_slice(MAGIC);
+    out.u16(VERSION);
+    let mut flags = 0;
+    if program.debug.is_some() {
+        flags |= HAS_DEBUG;
+    }
+    if program.jump_targets.is_some() {
+        flags |= HAS_JUMP_TARGETS;
+    }
+    out.u16(flags);
+    out.len(constants.len())?;
+    for constant in constants {
+        out.bytes.extend_from_slice(&constant.to_le_bytes());
+    }
+    out.bytes.extend(code.bytes);
+    if let Some(targets) = &program.jump_targets {
+        out.len(targets.len())?;
+        for target in targets.iter() {
+            out.len(*target)?;
+        }
+    }
+    if let Some(debug) = &program.debug {
+        let source_map = &debug.source_map;
+        out.len(source_map.files.len())?;
+        for file in source_map.files.iter() {
+            out.str(&file.name)?;
+            out.len(file.lines.len())?;
+            for line in file.lines.iter() {
+                out.str(line)?;
+            }
+        }
+        if source_map.instruction_files.len() != source_map.instruction_lines.len() {
+            return Err(bad_bytecode("source map files and lines differ in length"));
+        }
+        out.len(source_map.instruction_lines.len())?;
+        let locations = source_map
+            .instruction_files
+            .iter()
+            .zip(source_map.instruction_lines.iter());
+        for (file, line) in locations {
+            out.len(*file)?;
+            out.len(*line)?;
+        }
+        out.table(&debug.labels)?;
+        out.table(&debug.variables)?;
+        out.len(debug.frames.len())?;
+        for (enter, frame) in debug.frames.iter() {
+            out.len(*enter)?;
+            out.len(frame.end)?;
+            out.len(frame.slots.len())?;
+            for slot in frame.slots.iter() {
+                out.str(slot)?;
+            }
+        }
+    }
+    Ok(out.bytes)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Program, ExecError> {
+    let mut input = Decoder { bytes, pos: 0 };
+    if input.take(MAGIC.len())? != MAGIC {
+        return Err(bad_bytecode("not a spyder bytecode file"));
+    }
+    let version = input.u16()?;
+    if version != VERSION {
+        return Err(bad_bytecode(&format!(
+            "unsupported bytecode version {}, expected {}",
+            version, VERSION
+        )));
+    }
+    let flags = input.u16()?;
+    let mut constants = Vec::new();
+    for _ in 0..input.len()? {
+        let mut val = [0; 8];
+        val.copy_from_slice(input.take(8)?);
+        constants.push(i64::from_le_bytes(val));
+    }
+    let mut instructions = Vec::new();
+    for _ in 0..input.len()? {
+        instructions.push(input.instruction(&constants)?);
+    }
+    let jump_targets = if flags & HAS_JUMP_TARGETS != 0 {
+        let mut targets = BTreeSet::new();
+        for _ in 0..input.len()? {
+            targets.insert(input.len()?);
+        }
+        Some(targets)
+    } else {
+        None
+    };
+    let debug = if flags & HAS_DEBUG != 0 {
+        let mut files = Vec::new();
+        for _ in 0..input.len()? {
+            let name = input.str()?;
+            let mut lines = Vec::new();
+            for _ in 0..input.len()? {
+                lines.push(input.str()?);
+            }
+            files.push(SourceFile { name, lines });
+        }
+        let mut instruction_files = Vec::new();
+        let mut instruction_lines = Vec::new();
+        for _ in 0..input.len()? {
+            instruction_files.push(input.len()?);
+            instruction_lines.push(input.len()?);
+        }
+        Some(DebugInfo {
+            source_map: SourceMap {
+                files,
+                instruction_files,
+                instruction_lines,
+            },
+            labels: input.table()?,
+            variables: input.table()?,
+            frames: input.frames()?,
+        })
+    } else {
+        None
+    };
+    if input.pos != bytes.len() {
+        return Err(bad_bytecode("trailing bytes after program"));
+    }
+    if let Some(debug) = &debug {
+        check_debug(debug, &instructions)?;
+    }
+    Ok(Program {
+        instructions,
+        debug,
+        jump_targets,
+    })
+}
+
+// the debug section is trusted by error messages, tracing and the debugger
+// so it has to describe the instructions it came with
+fn check_debug(debug: &DebugInfo, instructions: &[Instruction]) -> Result<(), ExecError> {
+    let source_map = &debug.source_map;
+    if source_map.instruction_lines.len() != instructions.len() {
+        return Err(bad_bytecode("source map does not cover every instruction"));
+    }
+    let locations = source_map
+        .instruction_files
+        .iter()
+        .zip(source_map.instruction_lines.iter());
+    for (file, line) in locations {
+        let lines = match source_map.files.get(*file) {
+            Some(file) => file.lines.len(),
+            None => return Err(bad_bytecode(&format!("no source file at index {}", file))),
+        };
+        if !(1..=lines).contains(line) {
+            return Err(bad_bytecode(&format!(
+                "no line {} in source file {}",
+                line, file
+            )));
+        }
+    }
+    for (enter, frame) in debug.frames.iter() {
+        let size = match instructions.get(*enter) {
+            Some(Enter(params, size)) => *size.max(params),
+            _ => return Err(bad_bytecode(&format!("no Enter at frame index {}", enter))),
+        };
+        if frame.end <= *enter || frame.end > instructions.len() {
+            return Err(bad_bytecode(&format!(
+                "frame at {} ends at {}",
+                enter, frame.end
+            )));
+        }
+        if frame.slots.len() > size {
+            return Err(bad_bytecode(&format!(
+                "frame at {} names {} slots but holds {}",
+                enter,
+                frame.slots.len(),
+                size
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn bad_bytecode(details: &str) -> ExecError {
+    ExecError::BadBytecode(details.to_string())
+}
+
+fn opcode(instruction: &Instruction) -> u8 {
+    match instruction {
+        Push(_) => 0,
+        Load => 1,
+        Store => 2,
+        Pop => 3,
+        Goto(_) => 4,
+        GotoEqual(_) => 5,
+        GotoNotEqual(_) => 6,
+        GotoLess(_) => 7,
+        GotoGreater(_) => 8,
+        GotoLessEqual(_) => 9,
+        GotoGreaterEqual(_) => 10,
+        RePush => 11,
+        ClearStack => 12,
+        Call(_) => 13,
+        Ret => 14,
+        Enter(_, _) => 15,
+        LoadLocal(_) => 16,
+        StoreLocal(_) => 17,
+        NoOp => 18,
+        Add => 19,
+        Sub => 20,
+        Mul => 21,
+        Div => 22,
+        Mod => 23,
+        Neg => 24,
+        Eq => 25,
+        Ne => 26,
+        Lt => 27,
+        Gt => 28,
+        Le => 29,
+        Ge => 30,
+        Halt(_) => 31,
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    bytes: Vec<u8>,
+}
+
+impl Encoder {
+    fn u8(&mut self, val: u8) {
+        self.bytes.push(val);
+    }
+
+    fn u16(&mut self, val: u16) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    // lengths, indices and operands are all stored as u32
+    fn len(&mut self, val: usize) -> Result<(), ExecError> {
+        let val = u32::try_from(val).map_err(|_| bad_bytecode("program too large to encode"))?;
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+        Ok(())
+    }
+
+    fn str(&mut self, val: &str) -> Result<(), ExecError> {
+        self.len(val.len())?;
+        self.bytes.extend_from_slice(val.as_bytes());
+        Ok(())
+    }
+
+    fn table(&mut self, table: &BTreeMap<String, usize>) -> Result<(), ExecError> {
+        self.len(table.len())?;
+        for (name, val) in table.iter() {
+            self.str(name)?;
+            self.len(*val)?;
+        }
+        Ok(())
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], ExecError> {
+        let end = self
+            .pos
+            .checked_add(count)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| bad_bytecode("unexpected end of file"))?;
+        let taken = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, ExecError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ExecError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn len(&mut self) -> Result<usize, ExecError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+    }
+
+    fn str(&mut self) -> Result<String, ExecError> {
+        let len = self.len()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| bad_bytecode("string is not valid UTF-8"))
+    }
+
+    fn target(&mut self) -> Result<Target, ExecError> {
+        match self.u8()? {
+            0 => Ok(Target::Stack),
+            1 => Ok(Target::Addr(self.len()?)),
+            tag => Err(bad_bytecode(&format!("unknown jump target kind {}", tag))),
+        }
+    }
+
+    fn constant(&mut self, constants: &[i64]) -> Result<i64, ExecError> {
+        let idx = self.len()?;
+        match constants.get(idx) {
+            Some(val) => Ok(*val),
+            None => Err(bad_bytecode(&format!("no constant at index {}", idx))),
+        }
+    }
+
+    fn table(&mut self) -> Result<BTreeMap<String, usize>, ExecError> {
+        let mut table = BTreeMap::new();
+        for _ in 0..self.len()? {
+            let name = self.str()?;
+            table.insert(name, self.len()?);
+        }
+        Ok(table)
+    }
+
+    fn frames(&mut self) -> Result<BTreeMap<usize, FrameLayout>, ExecError> {
+        let mut frames = BTreeMap::new();
+        for _ in 0..self.len()? {
+            let enter = self.len()?;
+            let end = self.len()?;
+            let mut slots = Vec::new();
+            for _ in 0..self.len()? {
+                slots.push(self.str()?);
+            }
+            frames.insert(enter, FrameLayout { end, slots });
+        }
+        Ok(frames)
+    }
+
+    fn instruction(&mut self, constants: &[i64]) -> Result<Instruction, ExecError> {
+        let opcode = self.u8()?;
+        Ok(match opcode {
+            0 => Push(self.constant(constants)?),
+            1 => Load,
+            2 => Store,
+            3 => Pop,
+            4 => Goto(self.target()?),
+            5 => GotoEqual(self.target()?),
+            6 => GotoNotEqual(self.target()?),
+            7 => GotoLess(self.target()?),
+            8 => GotoGreater(self.target()?),
+            9 => GotoLessEqual(self.target()?),
+            10 => GotoGreaterEqual(self.target()?),
+            11 => RePush,
+            12 => ClearStack,
+            13 => Call(self.len()?),
+            14 => Ret,
+            15 => {
+                let params = self.len()?;
+                Enter(params, self.len()?)
+            }
+            16 => LoadLocal(self.len()?),
+            17 => StoreLocal(self.len()?),
+            18 => NoOp,
+            19 => Add,
+            20 => Sub,
+            21 => Mul,
+            22 => Div,
+            23 => Mod,
+            24 => Neg,
+            25 => Eq,
+            26 => Ne,
+            27 => Lt,
+            28 => Gt,
+            29 => Le,
+            30 => Ge,
+            31 => match self.u8()? {
+                0 => Halt(None),
+                1 => Halt(Some(self.constant(constants)?)),
+                tag => return Err(bad_bytecode(&format!("unknown halt kind {}", tag))),
+            },
+            _ => return Err(bad_bytecode(&format!("unknown opcode {}", opcode))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn program() -> Program {
+        Program {
+            instructions: vec![
+                Push(-7),
+                Push(-7),
+                Push(i64::MAX),
+                Enter(2, 3),
+                Call(0),
+                GotoEqual(Target::Addr(6)),
+                Goto(Target::Stack),
+                Ge,
+                Halt(Some(-7)),
+                Halt(None),
+            ],
+            debug: None,
+            jump_targets: Some(BTreeSet::from([1, 5, 6])),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = encode(&program()).unwrap();
+        assert_eq!(decode(&bytes), Ok(program()));
+    }
+
+    #[test]
+    fn test_constants_are_pooled() {
+        let bytes = encode(&program()).unwrap();
+        // magic, version, flags then the pool count
+        assert_eq!(&bytes[8..12], &2u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_rejects_bad_header() {
+        let mut bytes = encode(&program()).unwrap();
+        bytes[4] = 9;
+        assert!(matches!(decode(&bytes), Err(ExecError::BadBytecode(_))));
+        assert!(matches!(decode(b"SPD"), Err(ExecError::BadBytecode(_))));
+        assert!(matches!(
+            decode(b"ELF\0\0\0"),
+            Err(ExecError::BadBytecode(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_bad_debug_info() {
+        let debug_program = || {
+            let mut program = program();
+            let lines = vec![1; program.instructions.len()];
+            let mut debug = DebugInfo {
+                source_map: SourceMap::new("test.spd", "push 1\nfunc f(a)", lines),
+                ..DebugInfo::default()
+            };
+            debug.frames.insert(
+                3,
+                FrameLayout {
+                    end: 5,
+                    slots: vec!["a".to_string()],
+                },
+            );
+            program.debug = Some(debug);
+            program
+        };
+        let bytes = encode(&debug_program()).unwrap();
+        assert_eq!(decode(&bytes), Ok(debug_program()));
+        let corruptions: [fn(&mut DebugInfo); 5] = [
+            |debug| debug.source_map.instruction_lines[2] = 0,
+            |debug| debug.source_map.instruction_lines[2] = 3,
+            |debug| debug.source_map.instruction_files[2] = 1,
+            |debug| {
+                debug.source_map.instruction_lines.pop();
+                debug.source_map.instruction_files.pop();
+            },
+            |debug| debug.frames.get_mut(&3).unwrap().slots = vec![String::new(); 4],
+        ];
+        for corrupt in corruptions.iter() {
+            let mut program = debug_program();
+            corrupt(program.debug.as_mut().unwrap());
+            let bytes = encode(&program).unwrap();
+            assert!(matches!(decode(&bytes), Err(ExecError::BadBytecode(_))));
+        }
+    }
+
+    #[test]
+    fn test_rejects_truncated_code() {
+        let bytes = encode(&program()).unwrap();
+        let res = decode(&bytes[..bytes.len() - 1]);
+        assert!(matches!(res, Err(ExecError::BadBytecode(_))));
+    }
+}
@@ -1,70 +1,275 @@
 // Copyright 2021 The Spyder Authors.
 // Use of this source code is governed by the MIT License which can be
 // found in the LICENSE file.
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use crate::parser::{Assignment, Code, Statement, VarAccess, VariableExpr};
-use crate::types::{ExecError, Instruction};
+use crate::parser::{
+    Assignment, Code, Condition, Expr, FuncDef, LoadOrStore, Statement, VarAccess, VariableExpr,
+};
+use crate::types::{ExecError, FrameLayout, Instruction, Target};
 
-pub struct CodeGen<'a> {
-    labels: HashMap<&'a str, usize>,
-    // stores the label and index of the push that should
-    // push the index of what that label refers to
-    label_refs: Vec<(&'a str, usize)>,
+#[derive(Clone)]
+pub struct CodeGen {
+    labels: HashMap<String, usize>,
+    // stores the label and index of the push or call that should
+    // refer to the index of what that label refers to
+    label_refs: Vec<(String, usize, Origin)>,
     labels_resolved: Vec<Instruction>,
     curr_base_idx: usize,
-    variable_names: HashMap<&'a str, usize>,
+    variable_names: HashMap<String, usize>,
     curr_variable_allocation_idx: usize,
+    // the source file and line of every instruction in labels_resolved
+    instruction_files: Vec<usize>,
+    instruction_lines: Vec<usize>,
+    curr_file: usize,
+    // the name of the file being lowered when it is an imported module
+    curr_file_name: Option<String>,
+    curr_line: usize,
+    // the macro the statement being lowered was expanded from
+    curr_note: Option<Box<str>>,
+    // prefixed to the labels and globals of an imported module
+    namespace: Option<String>,
+    // the function being lowered if inside a func block
+    func: Option<FuncScope>,
+    // the if and while blocks being lowered, innermost last
+    blocks: Vec<Block>,
+    // used to make generated labels unique
+    generated_label_count: usize,
+    // the frame of every function lowered so far by its Enter index
+    frames: BTreeMap<usize, FrameLayout>,
 }
 
-impl<'a> CodeGen<'a> {
+#[derive(Clone)]
+struct FuncScope {
+    name: String,
+    origin: Origin,
+    params: usize,
+    // index of the Enter instruction, patched with the frame size at `end`
+    enter_idx: usize,
+    // generated label just past the function body
+    skip_label: String,
+    // frame slot of each parameter and local variable
+    locals: HashMap<String, usize>,
+}
+
+#[derive(Clone)]
+enum Block {
+    If {
+        origin: Origin,
+        // where a false condition jumps to, the else branch if there is one
+        else_label: String,
+        end_label: String,
+        seen_else: bool,
+    },
+    While {
+        origin: Origin,
+        start_label: String,
+        end_label: String,
+    },
+}
+
+// the statement an error is reported at
+#[derive(Clone)]
+struct Origin {
+    file: Option<String>,
+    line: usize,
+    note: Option<Box<str>>,
+}
+
+impl Origin {
+    fn mismatch(&self, details: String) -> ExecError {
+        ExecError::BlockMismatch {
+            file: self.file.clone(),
+            line: self.line,
+            details,
+            note: self.note.clone(),
+        }
+    }
+}
+
+impl Block {
+    fn origin(&self) -> &Origin {
+        match self {
+            Block::If { origin, .. } | Block::While { origin, .. } => origin,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Block::If { .. } => "if",
+            Block::While { .. } => "while",
+        }
+    }
+}
+
+impl Default for CodeGen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CodeGen {
     #[allow(non_snake_case)]
-    pub fn lower_IR(&mut self, input: &'a Code) -> Result<Vec<Instruction>, ExecError> {
-        for stmt in input.lines.iter() {
-            self.curr_base_idx = self.labels_resolved.len();
-            self.lower_statement(stmt)?;
+    pub fn lower_IR(&mut self, input: &Code) -> Result<Vec<Instruction>, ExecError> {
+        for (stmt, span) in input.lines.iter().zip(input.spans.iter()) {
+            self.lower_line(stmt, span.line)?;
+        }
+        self.resolve()
+    }
+
+    /// Lowers one more statement coming from the given source line, the
+    /// instructions become available from resolve
+    pub fn lower_line(&mut self, stmt: &Statement, line: usize) -> Result<(), ExecError> {
+        self.lower_expanded_line(stmt, line, None)
+    }
+
+    /// Like lower_line for a statement a macro call on the line expanded
+    /// to, errors it causes carry the note saying where in the macro it is
+    pub fn lower_expanded_line(
+        &mut self,
+        stmt: &Statement,
+        line: usize,
+        note: Option<Box<str>>,
+    ) -> Result<(), ExecError> {
+        self.curr_base_idx = self.labels_resolved.len();
+        self.curr_line = line;
+        self.curr_note = note;
+        self.lower_statement(stmt)?;
+        self.instruction_files
+            .resize(self.labels_resolved.len(), self.curr_file);
+        self.instruction_lines
+            .resize(self.labels_resolved.len(), line);
+        Ok(())
+    }
+
+    /// Sets the file the following statements come from and, for an
+    /// imported module, the name errors in it are reported with and the
+    /// namespace its labels and globals are put in
+    pub fn set_source(&mut self, file: usize, name: Option<&str>, namespace: Option<&str>) {
+        self.curr_file = file;
+        self.curr_file_name = name.map(str::to_string);
+        self.namespace = namespace.map(str::to_string);
+    }
+
+    /// Whether a block such as a func has been opened but not yet ended
+    pub fn in_block(&self) -> bool {
+        self.func.is_some() || !self.blocks.is_empty()
+    }
+
+    /// Every instruction lowered so far with jump and call targets filled
+    /// in, which fails if a label is not defined yet or a block is still open
+    pub fn resolve(&mut self) -> Result<Vec<Instruction>, ExecError> {
+        if let Some(block) = self.blocks.last() {
+            let details = format!("{} is missing its end", block.name());
+            return Err(block.origin().mismatch(details));
+        }
+        if let Some(func) = &self.func {
+            let details = format!("func {} is missing its end", func.name);
+            return Err(func.origin.mismatch(details));
         }
         // fill in go to destinations using labels map
-        for (label, position) in self.label_refs.iter() {
-            let jump_dest = match self.labels.get(*label) {
+        for (label, position, origin) in self.label_refs.iter() {
+            let jump_dest = match self.labels.get(label) {
                 Some(&idx) => idx,
                 None => {
-                    let mut error_string = "Could not find matching label to: ".to_owned();
-                    error_string.push_str(*label);
-                    return Err(ExecError::new(error_string.as_str()));
+                    return Err(ExecError::UndefinedLabel {
+                        file: origin.file.clone(),
+                        label: label.to_string(),
+                        line: origin.line,
+                        note: origin.note.clone(),
+                    })
                 }
             };
-            self.labels_resolved[*position] = Instruction::Push(jump_dest as i64)
+            let instruction = &mut self.labels_resolved[*position];
+            match instruction.jump_target_mut() {
+                Some(target) => *target = Target::Addr(jump_dest),
+                None => *instruction = Instruction::Call(jump_dest),
+            }
         }
         Ok(self.labels_resolved.clone())
     }
 
-    fn lower_statement(&mut self, stmt: &'a Statement) -> Result<(), ExecError> {
+    /// The source line each lowered instruction came from, used to build a
+    /// SourceMap
+    pub fn instruction_lines(&self) -> &[usize] {
+        &self.instruction_lines
+    }
+
+    /// The index of the source file each lowered instruction came from, as
+    /// given to set_source
+    pub fn instruction_files(&self) -> &[usize] {
+        &self.instruction_files
+    }
+
+    /// Every label defined so far and the instruction it refers to
+    pub fn labels(&self) -> &HashMap<String, usize> {
+        &self.labels
+    }
+
+    /// Every index a jump may legally land on: the labels defined so far,
+    /// generated ones included, and the instruction after every call
+    pub fn jump_targets(&self) -> BTreeSet<usize> {
+        let returns = self
+            .labels_resolved
+            .iter()
+            .enumerate()
+            .filter(|(_, instruction)| matches!(instruction, Instruction::Call(_)))
+            .map(|(idx, _)| idx + 1);
+        self.labels.values().copied().chain(returns).collect()
+    }
+
+    /// The names of the frame slots of every function lowered so far, keyed
+    /// by the index of its Enter
+    pub fn frames(&self) -> &BTreeMap<usize, FrameLayout> {
+        &self.frames
+    }
+
+    /// Every global variable declared so far and its memory address
+    pub fn variable_names(&self) -> &HashMap<String, usize> {
+        &self.variable_names
+    }
+
+    fn lower_statement(&mut self, stmt: &Statement) -> Result<(), ExecError> {
         match stmt {
             Statement::LabeledStatement(labled_statement) => {
                 self.labels
-                    .insert(labled_statement.label, self.curr_base_idx);
-                self.lower_statement(&*labled_statement.statement)?;
+                    .insert(self.qualify(labled_statement.label), self.curr_base_idx);
+                self.lower_statement(&labled_statement.statement)?;
             }
-            Statement::Goto(goto) => {
-                self.label_refs.push((*goto, self.curr_base_idx));
-                self.labels_resolved.push(Instruction::Push(0));
-                self.labels_resolved.push(Instruction::Goto);
+            Statement::Goto(label) => self.lower_jump(&self.qualify(label), Instruction::Goto),
+            Statement::GotoEqual(label) => {
+                self.lower_jump(&self.qualify(label), Instruction::GotoEqual)
             }
-            Statement::GotoEqual(goto_if_equal) => {
-                self.label_refs.push((*goto_if_equal, self.curr_base_idx));
-                self.labels_resolved.push(Instruction::Push(0));
-                self.labels_resolved.push(Instruction::GotoEqual);
+            Statement::GotoNotEqual(label) => {
+                self.lower_jump(&self.qualify(label), Instruction::GotoNotEqual)
+            }
+            Statement::GotoLess(label) => {
+                self.lower_jump(&self.qualify(label), Instruction::GotoLess)
+            }
+            Statement::GotoGreater(label) => {
+                self.lower_jump(&self.qualify(label), Instruction::GotoGreater)
+            }
+            Statement::GotoLessEqual(label) => {
+                self.lower_jump(&self.qualify(label), Instruction::GotoLessEqual)
+            }
+            Statement::GotoGreaterEqual(label) => {
+                self.lower_jump(&self.qualify(label), Instruction::GotoGreaterEqual)
             }
             Statement::Call(label) => {
-                // push return addr
-                self.labels_resolved.push(Instruction::ClearStack);
-                self.labels_resolved
-                    .push(Instruction::Push((self.curr_base_idx + 4) as i64));
-                self.label_refs.push((*label, self.curr_base_idx + 2));
-                self.labels_resolved.push(Instruction::Push(0));
-                self.labels_resolved.push(Instruction::Goto);
+                let label = self.qualify(label);
+                self.lower_jump(&label, |_| Instruction::Call(0));
             }
+            Statement::Import(path) => {
+                return Err(ExecError::Import(format!(
+                    "{} can only be imported from a file",
+                    path
+                )))
+            }
+            Statement::Func(func) => self.lower_func(func)?,
+            Statement::If(cond) => self.lower_if(cond)?,
+            Statement::Else => self.lower_else()?,
+            Statement::While(cond) => self.lower_while(cond)?,
+            Statement::End => self.lower_end()?,
             Statement::VarExpr(var_expr) => self.lower_var_expr(var_expr)?,
             Statement::Ins(instruction) => self.labels_resolved.push(instruction.clone()),
             Statement::Comment(_) => (),
@@ -72,24 +277,228 @@ impl<'a> CodeGen<'a> {
         Ok(())
     }
 
-    fn lower_var_expr(&mut self, var_expr: &'a VariableExpr) -> Result<(), ExecError> {
+    // names inside a module are put in its namespace unless they already
+    // name one
+    fn qualify(&self, name: &str) -> String {
+        match &self.namespace {
+            Some(namespace) if !name.contains("::") => format!("{}::{}", namespace, name),
+            _ => name.to_string(),
+        }
+    }
+
+    // pushes the jump or call with a placeholder for the label's address
+    fn lower_jump(&mut self, label: &str, jump: fn(Target) -> Instruction) {
+        self.label_refs
+            .push((label.to_string(), self.labels_resolved.len(), self.origin()));
+        self.labels_resolved.push(jump(Target::Addr(0)));
+    }
+
+    // the statement being lowered
+    fn origin(&self) -> Origin {
+        Origin {
+            file: self.curr_file_name.clone(),
+            line: self.curr_line,
+            note: self.curr_note.clone(),
+        }
+    }
+
+    // returns a label that cannot clash with one written in source since
+    // those are alphanumeric
+    fn generate_label(&mut self, kind: &str) -> String {
+        self.generated_label_count += 1;
+        format!("{}.{}", kind, self.generated_label_count)
+    }
+
+    // functions are jumped over when execution reaches them and start by
+    // moving their arguments off the stack into a fresh frame
+    fn lower_func(&mut self, func: &FuncDef) -> Result<(), ExecError> {
+        if let Some(outer) = &self.func {
+            let details = format!("func {} is nested inside func {}", func.name, outer.name);
+            return Err(self.origin().mismatch(details));
+        }
+        if let Some(block) = self.blocks.last() {
+            let details = format!("func {} is nested inside {}", func.name, block.name());
+            return Err(self.origin().mismatch(details));
+        }
+        let skip_label = self.generate_label("endfunc");
+        self.lower_jump(&skip_label, Instruction::Goto);
+        let enter_idx = self.labels_resolved.len();
+        self.labels.insert(self.qualify(func.name), enter_idx);
+        self.labels_resolved
+            .push(Instruction::Enter(func.params.len(), 0));
+        let locals = func
+            .params
+            .iter()
+            .enumerate()
+            .map(|(slot, param)| (param.to_string(), slot))
+            .collect();
+        self.func = Some(FuncScope {
+            name: func.name.to_string(),
+            origin: self.origin(),
+            params: func.params.len(),
+            enter_idx,
+            skip_label,
+            locals,
+        });
+        Ok(())
+    }
+
+    // a false condition skips to the else branch or past the end
+    fn lower_if(&mut self, cond: &Condition) -> Result<(), ExecError> {
+        let else_label = self.generate_label("else");
+        let end_label = self.generate_label("endif");
+        self.lower_condition(cond)?;
+        self.lower_jump(&else_label, Instruction::GotoEqual);
+        self.blocks.push(Block::If {
+            origin: self.origin(),
+            else_label,
+            end_label,
+            seen_else: false,
+        });
+        Ok(())
+    }
+
+    fn lower_else(&mut self) -> Result<(), ExecError> {
+        let (else_label, end_label) = match self.blocks.last_mut() {
+            Some(Block::If {
+                else_label,
+                end_label,
+                seen_else: seen_else @ false,
+                ..
+            }) => {
+                *seen_else = true;
+                (else_label.clone(), end_label.clone())
+            }
+            _ => {
+                let details = "else without a matching if".to_string();
+                return Err(self.origin().mismatch(details));
+            }
+        };
+        // the branch taken when the condition held jumps over this one
+        self.lower_jump(&end_label, Instruction::Goto);
+        self.labels.insert(else_label, self.labels_resolved.len());
+        Ok(())
+    }
+
+    // the condition is tested before every iteration, jumping past the
+    // end once it is false
+    fn lower_while(&mut self, cond: &Condition) -> Result<(), ExecError> {
+        let start_label = self.generate_label("while");
+        let end_label = self.generate_label("endwhile");
+        self.labels
+            .insert(start_label.clone(), self.labels_resolved.len());
+        self.lower_condition(cond)?;
+        self.lower_jump(&end_label, Instruction::GotoEqual);
+        self.blocks.push(Block::While {
+            origin: self.origin(),
+            start_label,
+            end_label,
+        });
+        Ok(())
+    }
+
+    // leaves 1 on the stack if the condition holds and 0 otherwise, or for
+    // a lone expression its value
+    fn lower_condition(&mut self, cond: &Condition) -> Result<(), ExecError> {
+        match cond {
+            Condition::NonZero(expr) => self.lower_expr(expr)?,
+            // comparisons test the top of the stack against the value below
+            Condition::Compare(left, comparison, right) => {
+                self.lower_expr(right)?;
+                self.lower_expr(left)?;
+                self.labels_resolved.push(comparison.get_value());
+            }
+        }
+        Ok(())
+    }
+
+    fn lower_end(&mut self) -> Result<(), ExecError> {
+        match self.blocks.pop() {
+            Some(Block::If {
+                else_label,
+                end_label,
+                seen_else,
+                ..
+            }) => {
+                if !seen_else {
+                    self.labels.insert(else_label, self.labels_resolved.len());
+                }
+                self.labels.insert(end_label, self.labels_resolved.len());
+                return Ok(());
+            }
+            Some(Block::While {
+                start_label,
+                end_label,
+                ..
+            }) => {
+                self.lower_jump(&start_label, Instruction::Goto);
+                self.labels.insert(end_label, self.labels_resolved.len());
+                return Ok(());
+            }
+            None => (),
+        }
+        match self.func.take() {
+            Some(func) => {
+                self.labels_resolved[func.enter_idx] =
+                    Instruction::Enter(func.params, func.locals.len());
+                // falling off the end of a function returns from it
+                self.labels_resolved.push(Instruction::Ret);
+                self.labels
+                    .insert(func.skip_label, self.labels_resolved.len());
+                let mut slots = vec![String::new(); func.locals.len()];
+                for (name, slot) in func.locals {
+                    slots[slot] = name;
+                }
+                self.frames.insert(
+                    func.enter_idx,
+                    FrameLayout {
+                        end: self.labels_resolved.len(),
+                        slots,
+                    },
+                );
+            }
+            None => {
+                let details = "end without a matching block".to_string();
+                return Err(self.origin().mismatch(details));
+            }
+        }
+        Ok(())
+    }
+
+    // the frame slots of the function currently being lowered if any
+    fn locals(&mut self) -> Option<&mut HashMap<String, usize>> {
+        self.func.as_mut().map(|func| &mut func.locals)
+    }
+
+    fn lower_var_expr(&mut self, var_expr: &VariableExpr) -> Result<(), ExecError> {
         match var_expr {
-            VariableExpr::Assignment(assignment_expr) => self.lower_assignment(assignment_expr),
+            VariableExpr::Assignment(assignment_expr) => self.lower_assignment(assignment_expr)?,
             VariableExpr::Access(access_expr) => self.lower_access(access_expr)?,
         }
         Ok(())
     }
 
-    fn lower_access(&mut self, access: &'a VarAccess) -> Result<(), ExecError> {
+    fn lower_access(&mut self, access: &VarAccess) -> Result<(), ExecError> {
         match access.name {
             None => self.labels_resolved.push(access.load_or_store.get_value()),
             Some(name) => {
-                let addr = match self.variable_names.get(name) {
+                if let Some(&slot) = self.locals().and_then(|locals| locals.get(name)) {
+                    self.labels_resolved.push(match access.load_or_store {
+                        LoadOrStore::Load => Instruction::LoadLocal(slot),
+                        LoadOrStore::Store => Instruction::StoreLocal(slot),
+                    });
+                    return Ok(());
+                }
+                let name = self.qualify(name);
+                let addr = match self.variable_names.get(&name) {
                     Some(addr) => *addr,
                     None => {
-                        let mut err_string = "uninitialized variable: ".to_string();
-                        err_string.push_str(name);
-                        return Err(ExecError::new(err_string.as_str()));
+                        return Err(ExecError::UndefinedVariable {
+                            file: self.curr_file_name.clone(),
+                            name,
+                            line: self.curr_line,
+                            note: self.curr_note.clone(),
+                        })
                     }
                 };
                 self.labels_resolved.push(Instruction::Push(addr as i64));
@@ -99,25 +508,50 @@ impl<'a> CodeGen<'a> {
         Ok(())
     }
 
-    fn lower_assignment(&mut self, assignment: &'a Assignment) {
-        match self.variable_names.get(assignment.name) {
-            Some(addr) => {
-                self.labels_resolved
-                    .push(Instruction::Push(assignment.value));
-                self.labels_resolved.push(Instruction::Push(*addr as i64));
-                self.labels_resolved.push(Instruction::Store);
-            }
+    fn lower_assignment(&mut self, assignment: &Assignment) -> Result<(), ExecError> {
+        // the value is computed first so it can read the variable's old value
+        self.lower_expr(&assignment.value)?;
+        // inside a function every var lives in the frame
+        if let Some(locals) = self.locals() {
+            let next_slot = locals.len();
+            let slot = *locals
+                .entry(assignment.name.to_string())
+                .or_insert(next_slot);
+            self.labels_resolved.push(Instruction::StoreLocal(slot));
+            return Ok(());
+        }
+        let name = self.qualify(assignment.name);
+        let addr = match self.variable_names.get(&name) {
+            Some(addr) => *addr,
             None => {
                 self.curr_variable_allocation_idx += 1;
                 self.variable_names
-                    .insert(assignment.name, self.curr_variable_allocation_idx);
-                self.labels_resolved
-                    .push(Instruction::Push(assignment.value));
-                self.labels_resolved
-                    .push(Instruction::Push(self.curr_variable_allocation_idx as i64));
-                self.labels_resolved.push(Instruction::Store);
+                    .insert(name, self.curr_variable_allocation_idx);
+                self.curr_variable_allocation_idx
+            }
+        };
+        self.labels_resolved.push(Instruction::Push(addr as i64));
+        self.labels_resolved.push(Instruction::Store);
+        Ok(())
+    }
+
+    // leaves the value of the expression on top of the stack
+    fn lower_expr(&mut self, expr: &Expr) -> Result<(), ExecError> {
+        match expr {
+            Expr::Number(val) => self.labels_resolved.push(Instruction::Push(*val)),
+            Expr::Variable(name) => self.lower_access(&VarAccess {
+                load_or_store: LoadOrStore::Load,
+                name: Some(name),
+            })?,
+            // binary instructions take their left operand from the top of
+            // the stack so the right one is pushed first
+            Expr::Binary(left, op, right) => {
+                self.lower_expr(right)?;
+                self.lower_expr(left)?;
+                self.labels_resolved.push(op.get_value());
             }
         }
+        Ok(())
     }
     pub fn new() -> Self {
         Self {
@@ -127,6 +561,155 @@ impl<'a> CodeGen<'a> {
             variable_names: HashMap::new(),
             curr_variable_allocation_idx: 0,
             curr_base_idx: 0,
+            instruction_files: vec![],
+            instruction_lines: vec![],
+            curr_file: 0,
+            curr_line: 0,
+            curr_note: None,
+            curr_file_name: None,
+            namespace: None,
+            func: None,
+            blocks: vec![],
+            generated_label_count: 0,
+            frames: BTreeMap::new(),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn test_instruction_lines() {
+        let parsed = parser::parse("push 1\ngoto END\n!![END] add").unwrap();
+        let mut compiler = CodeGen::new();
+        let lowered = compiler.lower_IR(&parsed).unwrap();
+        assert_eq!(lowered.len(), 3);
+        assert_eq!(compiler.instruction_lines(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn test_func_locals() {
+        let parsed = parser::parse("func f(a)\nvar b = 2\nload a\nstore b\nend").unwrap();
+        let lowered = CodeGen::new().lower_IR(&parsed).unwrap();
+        assert_eq!(
+            lowered,
+            vec![
+                Instruction::Goto(Target::Addr(7)),
+                Instruction::Enter(1, 2),
+                Instruction::Push(2),
+                Instruction::StoreLocal(1),
+                Instruction::LoadLocal(0),
+                Instruction::StoreLocal(1),
+                Instruction::Ret,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expressions() {
+        let parsed =
+            parser::parse("var a = 6\nvar b = a - 4\nfunc f(x)\nvar y = x * a\nend").unwrap();
+        let lowered = CodeGen::new().lower_IR(&parsed).unwrap();
+        assert_eq!(
+            lowered[3..10],
+            [
+                Instruction::Push(4),
+                Instruction::Push(1),
+                Instruction::Load,
+                Instruction::Sub,
+                Instruction::Push(2),
+                Instruction::Store,
+                Instruction::Goto(Target::Addr(17)),
+            ]
+        );
+        assert_eq!(
+            lowered[10..],
+            [
+                Instruction::Enter(1, 2),
+                Instruction::Push(1),
+                Instruction::Load,
+                Instruction::LoadLocal(0),
+                Instruction::Mul,
+                Instruction::StoreLocal(1),
+                Instruction::Ret,
+            ]
+        );
+        let parsed = parser::parse("var a = a + 1").unwrap();
+        let err = CodeGen::new().lower_IR(&parsed).unwrap_err();
+        assert!(matches!(err, ExecError::UndefinedVariable { line: 1, .. }));
+    }
+
+    #[test]
+    fn test_if_else() {
+        let parsed = parser::parse("if x\npush 1\nelse\npush 2\nend").unwrap();
+        let mut compiler = CodeGen::new();
+        compiler.variable_names.insert("x".to_string(), 1);
+        assert_eq!(
+            compiler.lower_IR(&parsed).unwrap(),
+            vec![
+                Instruction::Push(1),
+                Instruction::Load,
+                Instruction::GotoEqual(Target::Addr(5)),
+                Instruction::Push(1),
+                Instruction::Goto(Target::Addr(6)),
+                Instruction::Push(2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_while() {
+        let parsed = parser::parse("while 3 > 2\nnoOp\nend").unwrap();
+        assert_eq!(
+            CodeGen::new().lower_IR(&parsed).unwrap(),
+            vec![
+                Instruction::Push(2),
+                Instruction::Push(3),
+                Instruction::Gt,
+                Instruction::GotoEqual(Target::Addr(6)),
+                Instruction::NoOp,
+                Instruction::Goto(Target::Addr(0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jump_targets() {
+        let parsed = parser::parse("!![top] noOp\ncall top\nwhile 1\npush 1\nend").unwrap();
+        let mut compiler = CodeGen::new();
+        compiler.lower_IR(&parsed).unwrap();
+        // the label, the return from the call and both ends of the loop
+        assert_eq!(
+            compiler.jump_targets().into_iter().collect::<Vec<_>>(),
+            vec![0, 2, 6]
+        );
+    }
+
+    #[test]
+    fn test_unbalanced_blocks() {
+        let parsed = parser::parse("push 1\nend").unwrap();
+        let err = CodeGen::new().lower_IR(&parsed).unwrap_err();
+        assert!(matches!(err, ExecError::BlockMismatch { line: 2, .. }));
+        let parsed = parser::parse("func f()\nfunc g()\nend").unwrap();
+        let err = CodeGen::new().lower_IR(&parsed).unwrap_err();
+        assert!(matches!(err, ExecError::BlockMismatch { line: 2, .. }));
+        let parsed = parser::parse("push 1\nfunc f()\npush 2").unwrap();
+        let err = CodeGen::new().lower_IR(&parsed).unwrap_err();
+        assert!(matches!(err, ExecError::BlockMismatch { line: 2, .. }));
+        let parsed = parser::parse("while 1\nif 1\nend").unwrap();
+        let err = CodeGen::new().lower_IR(&parsed).unwrap_err();
+        assert!(matches!(err, ExecError::BlockMismatch { line: 1, .. }));
+        let parsed = parser::parse("while 1\nelse\nend").unwrap();
+        let err = CodeGen::new().lower_IR(&parsed).unwrap_err();
+        assert!(matches!(err, ExecError::BlockMismatch { line: 2, .. }));
+        let parsed = parser::parse("if 1\nelse\nelse\nend").unwrap();
+        let err = CodeGen::new().lower_IR(&parsed).unwrap_err();
+        assert!(matches!(err, ExecError::BlockMismatch { line: 3, .. }));
+        let parsed = parser::parse("if 1\nfunc f()\nend\nend").unwrap();
+        let err = CodeGen::new().lower_IR(&parsed).unwrap_err();
+        assert!(matches!(err, ExecError::BlockMismatch { line: 2, .. }));
+    }
+}
@@ -0,0 +1,382 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+//! An interactive debugger that steps through a program one instruction or
+//! source line at a time.
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use crate::interpreter::{Interpreter, StepResult};
+use crate::types::{ExecError, Program};
+
+const HELP: &str = "\
+break <label|line>   stop before the label or the first instruction of the line
+delete <label|line>  remove a breakpoint
+breakpoints          list breakpoints
+step                 execute one instruction
+next                 execute until the next source line, stepping over calls
+continue             execute until a breakpoint or the end of the program
+where                show the current instruction and source line
+stack                show the stack, top last
+push <value>         push a value onto the stack
+pop                  pop the top of the stack
+vars                 show global variables and those of the current function
+print <variable>     show a variable, parameter or local
+set <variable> <value>  change a variable, parameter or local
+quit                 leave the debugger";
+
+// where a variable named in a command lives
+#[derive(Debug, Clone, Copy)]
+enum Variable {
+    // index into the interpreter's locals
+    Local(usize),
+    // memory address
+    Global(usize),
+}
+
+pub struct Debugger {
+    vm: Interpreter,
+    program: Program,
+    // instruction indices to stop before
+    breakpoints: BTreeSet<usize>,
+    // set once the program has run off its end or hit an error
+    outcome: Option<Result<i64, ExecError>>,
+}
+
+impl Debugger {
+    pub fn new(program: Program) -> Self {
+        let mut vm = Interpreter::new();
+        vm.source_map = program.debug.as_ref().map(|info| info.source_map.clone());
+        vm.jump_targets = program.jump_targets.clone();
+        vm.load(program.instructions.clone());
+        Debugger {
+            vm,
+            program,
+            breakpoints: BTreeSet::new(),
+            outcome: None,
+        }
+    }
+
+    /// Reads commands until quit or the end of input
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        self.check_finished();
+        self.print_position(out)?;
+        write!(out, "(spyder) ")?;
+        out.flush()?;
+        for line in input.lines() {
+            if !self.command(line?.trim(), out)? {
+                return Ok(());
+            }
+            write!(out, "(spyder) ")?;
+            out.flush()?;
+        }
+        writeln!(out)
+    }
+
+    // returns false once the user asks to quit
+    fn command<W: Write>(&mut self, line: &str, out: &mut W) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => (),
+            ["quit"] | ["q"] => return Ok(false),
+            ["help"] | ["h"] => writeln!(out, "{}", HELP)?,
+            ["break", target] | ["b", target] => match self.resolve(target) {
+                Some(idx) => {
+                    self.breakpoints.insert(idx);
+                    writeln!(out, "breakpoint at instruction {}", idx)?;
+                }
+                None => writeln!(out, "no label or line {}", target)?,
+            },
+            ["delete", target] | ["d", target] => match self.resolve(target) {
+                Some(idx) if self.breakpoints.remove(&idx) => {
+                    writeln!(out, "removed breakpoint at instruction {}", idx)?
+                }
+                _ => writeln!(out, "no breakpoint at {}", target)?,
+            },
+            ["breakpoints"] => {
+                for idx in self.breakpoints.iter() {
+                    writeln!(out, "instruction {}", idx)?;
+                }
+            }
+            ["step"] | ["s"] => {
+                self.step();
+                self.print_position(out)?;
+            }
+            ["next"] | ["n"] => {
+                self.next();
+                self.print_position(out)?;
+            }
+            ["continue"] | ["c"] => {
+                self.step();
+                while self.outcome.is_none() && !self.at_breakpoint() {
+                    self.step();
+                }
+                self.print_position(out)?;
+            }
+            ["where"] | ["w"] => self.print_position(out)?,
+            ["stack"] => writeln!(out, "{:?}", self.vm.stack)?,
+            ["push", val] => match val.parse::<i64>() {
+                Ok(val) => self.vm.stack.push(val),
+                Err(_) => writeln!(out, "not a number: {}", val)?,
+            },
+            ["pop"] => match self.vm.stack.pop() {
+                Some(val) => writeln!(out, "{}", val)?,
+                None => writeln!(out, "the stack is empty")?,
+            },
+            ["vars"] => {
+                for (name, addr) in self.variables() {
+                    let val = self.vm.memory.load(addr as i64).unwrap_or(0);
+                    writeln!(out, "{} = {}", name, val)?;
+                }
+                match self.frame_slots() {
+                    Some(slots) => {
+                        for name in slots.iter() {
+                            if let Some(val) = self.variable(name).and_then(|var| self.load(var)) {
+                                writeln!(out, "{} = {}", name, val)?;
+                            }
+                        }
+                    }
+                    None if !self.vm.call_stack.is_empty() => {
+                        let frame = self.vm.locals.get(self.vm.frame_pointer..).unwrap_or(&[]);
+                        writeln!(out, "frame {:?}", frame)?;
+                    }
+                    None => (),
+                }
+            }
+            ["print", name] | ["p", name] => {
+                match self.variable(name).and_then(|var| self.load(var)) {
+                    Some(val) => writeln!(out, "{} = {}", name, val)?,
+                    None => writeln!(out, "no variable {}", name)?,
+                }
+            }
+            ["set", name, val] => match (self.variable(name), val.parse::<i64>()) {
+                (Some(var), Ok(val)) => {
+                    if self.store(var, val).is_none() {
+                        writeln!(out, "{} is outside of memory", name)?;
+                    }
+                }
+                (None, _) => writeln!(out, "no variable {}", name)?,
+                (_, Err(_)) => writeln!(out, "not a number: {}", val)?,
+            },
+            _ => writeln!(out, "unknown command {}, try help", line)?,
+        }
+        Ok(true)
+    }
+
+    fn step(&mut self) {
+        if self.outcome.is_some() {
+            return;
+        }
+        match self.vm.step() {
+            StepResult::Continue => (),
+            StepResult::Halted(val) => self.outcome = Some(Ok(val)),
+            StepResult::Error(err) => self.outcome = Some(Err(err)),
+        }
+    }
+
+    // steps until the source line changes without stopping inside calls
+    // made along the way, unless they hit a breakpoint
+    fn next(&mut self) {
+        let depth = self.vm.call_stack.len();
+        let line = self.current_line();
+        self.step();
+        while self.outcome.is_none() && !self.at_breakpoint() {
+            let returned_or_same_frame = self.vm.call_stack.len() <= depth;
+            if returned_or_same_frame && (line.is_none() || self.current_line() != line) {
+                break;
+            }
+            self.step();
+        }
+    }
+
+    fn check_finished(&mut self) {
+        if self.outcome.is_none() && self.vm.is_finished() {
+            self.outcome = Some(self.vm.last());
+        }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.breakpoints.contains(&self.vm.curr_instruction_idx)
+    }
+
+    // the file index and line of the current instruction
+    fn current_line(&self) -> Option<(usize, usize)> {
+        let source_map = &self.program.debug.as_ref()?.source_map;
+        let idx = self.vm.curr_instruction_idx;
+        Some((
+            *source_map.instruction_files.get(idx)?,
+            *source_map.instruction_lines.get(idx)?,
+        ))
+    }
+
+    // a label name, or a line of the file being debugged when there is
+    // debug info and an instruction index otherwise
+    fn resolve(&self, target: &str) -> Option<usize> {
+        let debug = self.program.debug.as_ref();
+        if let Ok(num) = target.parse::<usize>() {
+            return match debug {
+                Some(debug) => {
+                    let source_map = &debug.source_map;
+                    let mut lines = source_map
+                        .instruction_files
+                        .iter()
+                        .zip(source_map.instruction_lines.iter());
+                    lines.position(|(file, line)| *file == 0 && *line == num)
+                }
+                None if num < self.program.instructions.len() => Some(num),
+                None => None,
+            };
+        }
+        debug?.labels.get(target).copied()
+    }
+
+    fn variables(&self) -> Vec<(String, usize)> {
+        match &self.program.debug {
+            Some(debug) => debug
+                .variables
+                .iter()
+                .map(|(name, addr)| (name.clone(), *addr))
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    // the slot names of the function the current instruction belongs to,
+    // None outside of a function or before its Enter has run
+    fn frame_slots(&self) -> Option<&[String]> {
+        if self.vm.call_stack.is_empty() {
+            return None;
+        }
+        let idx = self.vm.curr_instruction_idx;
+        let frames = &self.program.debug.as_ref()?.frames;
+        match frames.range(..idx).next_back() {
+            Some((_, frame)) if idx < frame.end => Some(&frame.slots),
+            _ => None,
+        }
+    }
+
+    // parameters and locals of the current function hide globals
+    fn variable(&self, name: &str) -> Option<Variable> {
+        let slot = self
+            .frame_slots()
+            .and_then(|slots| slots.iter().position(|slot| slot == name));
+        match slot {
+            Some(slot) => Some(Variable::Local(self.vm.frame_pointer + slot)),
+            None => self
+                .program
+                .debug
+                .as_ref()?
+                .variables
+                .get(name)
+                .map(|addr| Variable::Global(*addr)),
+        }
+    }
+
+    fn load(&self, var: Variable) -> Option<i64> {
+        match var {
+            Variable::Local(idx) => self.vm.locals.get(idx).copied(),
+            Variable::Global(addr) => self.vm.memory.load(addr as i64),
+        }
+    }
+
+    fn store(&mut self, var: Variable, val: i64) -> Option<()> {
+        match var {
+            Variable::Local(idx) => {
+                *self.vm.locals.get_mut(idx)? = val;
+                Some(())
+            }
+            Variable::Global(addr) => self.vm.memory.store(addr as i64, val),
+        }
+    }
+
+    fn print_position<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match &self.outcome {
+            Some(Ok(val)) => return writeln!(out, "program finished with {}", val),
+            Some(Err(ExecError::StackUnderflow(_))) if self.vm.is_finished() => {
+                return writeln!(out, "program finished with an empty stack")
+            }
+            Some(Err(err)) => return writeln!(out, "error: {}", err),
+            None => (),
+        }
+        let idx = self.vm.curr_instruction_idx;
+        let instruction = &self.program.instructions[idx];
+        match self.vm.source_map.as_ref().and_then(|map| map.lookup(idx)) {
+            Some(location) => writeln!(out, "{}: {:?} at {}", idx, instruction, location),
+            None => writeln!(out, "{}: {:?}", idx, instruction),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codegen::CodeGen;
+    use crate::parser;
+    use crate::types::{DebugInfo, SourceMap};
+
+    const SOURCE: &str =
+        "var x = 2\npush 5\ncall double\ngoto end\n!![double] rePush\nadd\nreturn\n!![end] load x";
+
+    fn debugger(source: &str) -> Debugger {
+        let parsed = parser::parse(source).unwrap();
+        let mut compiler = CodeGen::new();
+        let instructions = compiler.lower_IR(&parsed).unwrap();
+        let debug = DebugInfo {
+            source_map: SourceMap::new("test.spd", source, compiler.instruction_lines().to_vec()),
+            labels: compiler.labels().clone().into_iter().collect(),
+            variables: compiler.variable_names().clone().into_iter().collect(),
+            frames: compiler.frames().clone(),
+        };
+        Debugger::new(Program {
+            instructions,
+            debug: Some(debug),
+            jump_targets: Some(compiler.jump_targets()),
+        })
+    }
+
+    fn session(source: &str, commands: &str) -> String {
+        let mut out = Vec::new();
+        debugger(source).run(commands.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_break_on_label() {
+        let out = session(SOURCE, "break double\ncontinue\nstack\n");
+        assert!(out.contains("breakpoint at instruction 6"));
+        assert!(out.contains("6: RePush at test.spd:5: !![double] rePush"));
+        assert!(out.contains("[5]"));
+    }
+
+    #[test]
+    fn test_next_steps_over_calls() {
+        let out = session(SOURCE, "next\nnext\nnext\nwhere\n");
+        assert!(out.contains("at test.spd:3: call double"));
+        assert!(out.contains("at test.spd:4: goto end"));
+    }
+
+    #[test]
+    fn test_modify_state() {
+        let out = session(
+            SOURCE,
+            "break 8\ncontinue\nset x 40\npush 7\npop\nprint x\ncontinue\n",
+        );
+        assert!(out.contains("(spyder) 7\n"));
+        assert!(out.contains("x = 40"));
+        assert!(out.contains("program finished with 40"));
+    }
+
+    #[test]
+    fn test_function_locals() {
+        let source = "func f(a)\nvar b = a + 1\nload b\nend\nvar a = 3\npush 4\ncall f";
+        let out = session(
+            source,
+            "break 3\ncontinue\nvars\nset a 9\nprint a\nstep\nstep\nprint a\n",
+        );
+        assert!(out.contains("a = 3\na = 4\nb = 5\n"));
+        assert!(out.contains("a = 9"));
+        // back at the top level a is the global again
+        assert!(out.ends_with("a = 3\n(spyder) \n"));
+    }
+}
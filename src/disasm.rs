@@ -0,0 +1,194 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+//! Turns lowered instructions back into readable assembly. Jump targets get
+//! a label, named after the source label when debug info is available, and
+//! pushes of global variable addresses are annotated with the variable.
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::types::{Instruction, Instruction::*, Program, Target};
+
+pub fn disassemble(program: &Program) -> String {
+    let instructions = &program.instructions;
+    let labels = label_names(program);
+    let mut variables: BTreeMap<usize, &str> = BTreeMap::new();
+    if let Some(debug) = &program.debug {
+        for (name, addr) in debug.variables.iter() {
+            variables.insert(*addr, name);
+        }
+    }
+
+    // imported files are named where their code starts
+    let multi_file = program
+        .debug
+        .as_ref()
+        .is_some_and(|debug| debug.source_map.files.len() > 1);
+    let mut out = String::new();
+    let mut last_file = None;
+    let mut last_line = None;
+    for (idx, instruction) in instructions.iter().enumerate() {
+        for name in labels.get(&idx).into_iter().flatten() {
+            writeln!(out, "{}:", name).unwrap();
+        }
+        let location = program
+            .debug
+            .as_ref()
+            .and_then(|debug| debug.source_map.lookup(idx));
+        if let Some(location) = location {
+            if multi_file && last_file.as_ref() != Some(&location.file) {
+                writeln!(out, "; {}", location.file).unwrap();
+                last_file = Some(location.file.clone());
+                last_line = None;
+            }
+            if last_line != Some(location.line) {
+                writeln!(out, "; {}: {}", location.line, location.text).unwrap();
+                last_line = Some(location.line);
+            }
+        }
+        let next = instructions.get(idx + 1);
+        let operand = match instruction {
+            Call(addr) => format!(" {}", target_name(&labels, *addr)),
+            Push(val) => match (next, variables.get(&(*val as usize))) {
+                (Some(Load), Some(name)) | (Some(Store), Some(name)) if *val >= 0 => {
+                    format!(" {:<12}; {}", val, name)
+                }
+                _ => format!(" {}", val),
+            },
+            Enter(params, size) => format!(" {} {}", params, size),
+            LoadLocal(slot) | StoreLocal(slot) => format!(" {}", slot),
+            Halt(Some(code)) => format!(" {}", code),
+            _ => match instruction.jump_target() {
+                Some(Target::Addr(addr)) => format!(" {}", target_name(&labels, addr)),
+                _ => String::new(),
+            },
+        };
+        writeln!(out, "{:>6}  {}{}", idx, mnemonic(instruction), operand).unwrap();
+    }
+    // a jump may target the end of the program
+    for name in labels.get(&instructions.len()).into_iter().flatten() {
+        writeln!(out, "{}:", name).unwrap();
+    }
+    out
+}
+
+/// The assembly mnemonic of an instruction, matching the source syntax
+/// where the instruction can be written directly
+pub fn mnemonic(instruction: &Instruction) -> &'static str {
+    match instruction {
+        Push(_) => "push",
+        Load => "load",
+        Store => "store",
+        Pop => "pop",
+        Goto(_) => "goto",
+        GotoEqual(_) => "gotoEqual",
+        GotoNotEqual(_) => "gotoNotEqual",
+        GotoLess(_) => "gotoLess",
+        GotoGreater(_) => "gotoGreater",
+        GotoLessEqual(_) => "gotoLessEqual",
+        GotoGreaterEqual(_) => "gotoGreaterEqual",
+        RePush => "rePush",
+        ClearStack => "clearStack",
+        Call(_) => "call",
+        Ret => "return",
+        Enter(_, _) => "enter",
+        LoadLocal(_) => "loadLocal",
+        StoreLocal(_) => "storeLocal",
+        Halt(_) => "halt",
+        NoOp => "noOp",
+        Add => "add",
+        Sub => "sub",
+        Mul => "mul",
+        Div => "div",
+        Mod => "mod",
+        Neg => "neg",
+        Eq => "eq",
+        Ne => "ne",
+        Lt => "lt",
+        Gt => "gt",
+        Le => "le",
+        Ge => "ge",
+    }
+}
+
+fn target_name(labels: &BTreeMap<usize, Vec<String>>, target: usize) -> String {
+    match labels.get(&target).and_then(|names| names.first()) {
+        Some(name) => name.clone(),
+        None => target.to_string(),
+    }
+}
+
+// every index that is a label in the source or the target of a jump or
+// call, along with the names to print for it
+fn label_names(program: &Program) -> BTreeMap<usize, Vec<String>> {
+    let instructions = &program.instructions;
+    let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    if let Some(debug) = &program.debug {
+        for (name, idx) in debug.labels.iter() {
+            labels.entry(*idx).or_default().push(name.clone());
+        }
+    }
+    for instruction in instructions.iter() {
+        let target = match (instruction, instruction.jump_target()) {
+            (Call(addr), _) => *addr,
+            (_, Some(Target::Addr(addr))) => addr,
+            _ => continue,
+        };
+        if target <= instructions.len() {
+            labels
+                .entry(target)
+                .or_insert_with(|| vec![format!("L{}", target)]);
+        }
+    }
+    labels
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::types::{DebugInfo, SourceMap};
+
+    #[test]
+    fn test_synthetic_labels() {
+        let program = Program {
+            instructions: vec![
+                Goto(Target::Addr(2)),
+                NoOp,
+                Call(1),
+                Push(9),
+                GotoEqual(Target::Addr(5)),
+                Goto(Target::Stack),
+            ],
+            debug: None,
+            jump_targets: None,
+        };
+        assert_eq!(
+            disassemble(&program),
+            "     0  goto L2\nL1:\n     1  noOp\nL2:\n     2  call L1\n     3  push 9\n     4  gotoEqual L5\nL5:\n     5  goto\n"
+        );
+    }
+
+    #[test]
+    fn test_debug_names() {
+        let mut debug = DebugInfo {
+            source_map: SourceMap::new(
+                "test.spd",
+                "var x = 4\n!![top] load x",
+                vec![1, 1, 1, 2, 2],
+            ),
+            ..DebugInfo::default()
+        };
+        debug.labels.insert("top".to_string(), 3);
+        debug.variables.insert("x".to_string(), 1);
+        let program = Program {
+            instructions: vec![Push(4), Push(1), Store, Push(1), Load],
+            debug: Some(debug),
+            jump_targets: None,
+        };
+        assert_eq!(
+            disassemble(&program),
+            "; 1: var x = 4\n     0  push 4\n     1  push 1           ; x\n     2  store\ntop:\n; 2: !![top] load x\n     3  push 1           ; x\n     4  load\n"
+        );
+    }
+}
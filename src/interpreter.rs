@@ -2,108 +2,756 @@
 // Use of this source code is governed by the MIT License which can be
 // found in the LICENSE file.
 
-use crate::types::{ExecError, Instruction, Instruction::*};
+use std::collections::BTreeSet;
+
+use crate::memory::Memory;
+use crate::types::{
+    ExecError, Instruction, Instruction::*, Snapshot, SourceMap, Target, STACK_SNAPSHOT_LEN,
+};
+
+/// What arithmetic instructions do when the result does not fit in an i64.
+/// Division and remainder by zero are always an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArithmeticMode {
+    // stop with ExecError::Overflow
+    #[default]
+    Trap,
+    // wrap around in two's complement
+    Wrapping,
+    // clamp to i64::MIN or i64::MAX
+    Saturating,
+}
+
+/// The outcome of executing a single instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepResult {
+    // there are more instructions to execute
+    Continue,
+    // the program ran off its end or executed Halt leaving this value on top
+    // of the stack, or Halt gave this value as its code
+    Halted(i64),
+    Error(ExecError),
+}
+
+/// How deep calls may nest unless configured otherwise
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
 
 pub struct Interpreter {
     pub stack: Vec<i64>,
-    pub vars: Vec<i64>,
+    pub memory: Memory,
     pub curr_instruction_idx: usize,
+    // one frame for every call currently executing
+    pub call_stack: Vec<Frame>,
+    pub max_call_depth: usize,
+    // slots of every entered function frame, the current one starts at
+    // frame_pointer
+    pub locals: Vec<i64>,
+    pub frame_pointer: usize,
+    pub arithmetic: ArithmeticMode,
+    // how many more instructions may execute, unlimited when None
+    pub fuel: Option<u64>,
+    // used to report source lines in errors and debug traces
+    pub source_map: Option<SourceMap>,
+    // where jumps that pop their destination may land, anywhere in the
+    // program when None
+    pub jump_targets: Option<BTreeSet<usize>>,
+    // the code given to the Halt that finished the program
+    exit_code: Option<i64>,
+    program: Vec<Instruction>,
 }
+/// What a call saves so Ret can restore the caller
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub return_addr: usize,
+    // the caller's frame pointer and how many locals it had
+    pub frame_pointer: usize,
+    pub locals_len: usize,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Interpreter {
     pub fn pop(&mut self) -> Result<i64, ExecError> {
         match self.stack.pop() {
             Some(num) => Ok(num),
-            None => Err(self.empty_stack_err()),
+            None => Err(ExecError::StackUnderflow(self.snapshot())),
         }
     }
-    pub fn empty_stack_err(&self) -> ExecError {
-        let mut error_msg = "stack empty at line: ".to_owned();
-        error_msg.push_str(self.curr_instruction_idx.to_string().as_str());
-        ExecError::new(error_msg.as_str())
+    /// Returns the value `depth` below the top of the stack without
+    /// removing it
+    pub fn peek(&self, depth: usize) -> Result<i64, ExecError> {
+        match self.stack.len().checked_sub(depth + 1) {
+            Some(idx) => Ok(self.stack[idx]),
+            None => Err(ExecError::StackUnderflow(self.snapshot())),
+        }
     }
-    pub fn get_val(&self, source: &i64) -> Result<i64, ExecError> {
-        Ok(self.vars[*source as usize])
+    /// Captures the current instruction and the top of the stack for
+    /// error reporting
+    pub fn snapshot(&self) -> Box<Snapshot> {
+        let stack_start = self.stack.len().saturating_sub(STACK_SNAPSHOT_LEN);
+        Box::new(Snapshot {
+            instruction_idx: self.curr_instruction_idx,
+            instruction: self.program.get(self.curr_instruction_idx).cloned(),
+            stack_top: self.stack[stack_start..].to_vec(),
+            location: self
+                .source_map
+                .as_ref()
+                .and_then(|map| map.lookup(self.curr_instruction_idx)),
+        })
     }
-    pub fn set_val(&mut self, dest: i64, val: i64) {
-        if self.vars.len() < dest as usize {
-            self.vars.resize((dest + 10) as usize, 0)
+    pub fn get_val(&self, source: i64) -> Result<i64, ExecError> {
+        match self.memory.load(source) {
+            Some(val) => Ok(val),
+            None => Err(self.bad_address(source)),
         }
-        self.vars[dest as usize] = val
     }
-    pub fn last(&self) -> Result<i64, ExecError> {
-        match self.stack.last() {
-            Some(num) => Ok(*num),
-            None => Err(self.empty_stack_err()),
+    pub fn set_val(&mut self, dest: i64, val: i64) -> Result<(), ExecError> {
+        match self.memory.store(dest, val) {
+            Some(()) => Ok(()),
+            None => Err(self.bad_address(dest)),
+        }
+    }
+    // index into locals of a slot in the current frame
+    fn local_idx(&self, slot: usize) -> Result<usize, ExecError> {
+        let idx = self.frame_pointer + slot;
+        if idx >= self.locals.len() {
+            return Err(self.bad_address(slot as i64));
         }
+        Ok(idx)
     }
+    fn bad_address(&self, address: i64) -> ExecError {
+        ExecError::BadAddress {
+            address,
+            at: self.snapshot(),
+        }
+    }
+    pub fn last(&self) -> Result<i64, ExecError> {
+        self.peek(0)
+    }
+    /// Loads and runs a program to completion, popping its result unless
+    /// it halted with a code
     pub fn run(&mut self, instructions: Vec<Instruction>, debug: bool) -> Result<i64, ExecError> {
-        while self.curr_instruction_idx < instructions.len() {
-            let instruction = &instructions[self.curr_instruction_idx];
-            if debug {
-                println!("stack {:?}", self.stack);
-                println!("{}: {:?}", self.curr_instruction_idx, instruction);
-            }
-            match instruction {
-                Push(val) => self.stack.push(*val),
-                Load => {
-                    let source = self.pop()?;
-                    // TODO improve error handling
-                    let val = self.get_val(&source).unwrap();
-                    self.stack.push(val);
+        self.load(instructions);
+        loop {
+            if debug && !self.is_finished() {
+                self.print_trace();
+            }
+            match self.step() {
+                StepResult::Continue => (),
+                StepResult::Halted(val) if self.exit_code.is_some() => return Ok(val),
+                StepResult::Halted(_) => return self.pop(),
+                StepResult::Error(err) => return Err(err),
+            }
+        }
+    }
+    /// Replaces the program and starts executing it from its first
+    /// instruction. The stack and memory are kept.
+    pub fn load(&mut self, instructions: Vec<Instruction>) {
+        self.program = instructions;
+        self.curr_instruction_idx = 0;
+        self.exit_code = None;
+        self.call_stack.clear();
+        self.locals.clear();
+        self.frame_pointer = 0;
+    }
+    /// Appends instructions to the loaded program without moving the current
+    /// position, so a program can be fed in piece by piece
+    pub fn extend(&mut self, instructions: &[Instruction]) {
+        self.program.extend_from_slice(instructions);
+        // execution carries on past a Halt into the new instructions
+        self.exit_code = None;
+    }
+    /// Executes the next instruction. Once the program has finished every
+    /// call returns Halted with the code it halted with, or else the top of
+    /// the stack which is left in place.
+    pub fn step(&mut self) -> StepResult {
+        if !self.is_finished() {
+            match self.fuel {
+                Some(0) => return StepResult::Error(ExecError::OutOfFuel(self.snapshot())),
+                Some(fuel) => self.fuel = Some(fuel - 1),
+                None => (),
+            }
+            if let Err(err) = self.execute_next() {
+                return StepResult::Error(err);
+            }
+            if !self.is_finished() {
+                return StepResult::Continue;
+            }
+        }
+        if let Some(code) = self.exit_code {
+            return StepResult::Halted(code);
+        }
+        match self.last() {
+            Ok(val) => StepResult::Halted(val),
+            Err(err) => StepResult::Error(err),
+        }
+    }
+    /// Executes at most n_steps instructions, stopping early if the program
+    /// halts or fails
+    pub fn run_for(&mut self, n_steps: usize) -> StepResult {
+        for _ in 0..n_steps {
+            match self.step() {
+                StepResult::Continue => (),
+                result => return result,
+            }
+        }
+        StepResult::Continue
+    }
+    /// Whether execution has run off the end of the program
+    pub fn is_finished(&self) -> bool {
+        self.curr_instruction_idx >= self.program.len()
+    }
+    // prints the stack and the instruction about to be executed
+    fn print_trace(&self) {
+        let instruction = &self.program[self.curr_instruction_idx];
+        println!("stack {:?}", self.stack);
+        match self
+            .source_map
+            .as_ref()
+            .and_then(|map| map.lookup(self.curr_instruction_idx))
+        {
+            Some(location) => println!(
+                "{}: {:?} ({})",
+                self.curr_instruction_idx, instruction, location
+            ),
+            None => println!("{}: {:?}", self.curr_instruction_idx, instruction),
+        }
+    }
+    /// Executes the instruction at curr_instruction_idx, the program must
+    /// not be finished
+    fn execute_next(&mut self) -> Result<(), ExecError> {
+        let instruction = self.program[self.curr_instruction_idx].clone();
+        // operands are peeked and only removed once the instruction
+        // can no longer fail so errors see the stack it started with
+        match instruction {
+            Push(val) => self.stack.push(val),
+            Load => {
+                let val = self.get_val(self.peek(0)?)?;
+                self.pop()?;
+                self.stack.push(val);
+            }
+            Store => {
+                let dest = self.peek(0)?;
+                let val = self.peek(1)?;
+                self.set_val(dest, val)?;
+                self.stack.truncate(self.stack.len() - 2);
+            }
+            Pop => {
+                self.pop()?;
+            }
+            Goto(target) => {
+                let (new_line, popped) = self.jump_dest(target)?;
+                self.stack.truncate(self.stack.len() - popped);
+                self.curr_instruction_idx = new_line;
+                return Ok(());
+            }
+            GotoEqual(target)
+            | GotoNotEqual(target)
+            | GotoLess(target)
+            | GotoGreater(target)
+            | GotoLessEqual(target)
+            | GotoGreaterEqual(target) => {
+                let (new_line, popped) = self.jump_dest(target)?;
+                let sentinal = self.peek(popped)?;
+                self.stack.truncate(self.stack.len() - popped - 1);
+                if branch_taken(&instruction, sentinal) {
+                    self.curr_instruction_idx = new_line;
+                    return Ok(());
                 }
-                Store => {
-                    let dest = self.pop()?;
-                    let val = self.pop()?;
-                    self.set_val(dest, val);
+            }
+            Call(addr) => {
+                self.check_jump(addr as i64)?;
+                if self.call_stack.len() >= self.max_call_depth {
+                    return Err(ExecError::CallDepthExceeded(self.snapshot()));
                 }
-                Pop => {
-                    self.pop()?;
+                self.call_stack.push(Frame {
+                    return_addr: self.curr_instruction_idx + 1,
+                    frame_pointer: self.frame_pointer,
+                    locals_len: self.locals.len(),
+                });
+                self.curr_instruction_idx = addr;
+                return Ok(());
+            }
+            Ret => match self.call_stack.pop() {
+                Some(frame) => {
+                    self.locals.truncate(frame.locals_len);
+                    self.frame_pointer = frame.frame_pointer;
+                    self.curr_instruction_idx = frame.return_addr;
+                    return Ok(());
                 }
-                Goto => {
-                    let new_line = self.pop()?;
-                    // need to subtract 1 because i is incremented every time
-                    self.curr_instruction_idx = new_line as usize - 1;
+                None => return Err(ExecError::ReturnWithoutCall(self.snapshot())),
+            },
+            Halt(code) => {
+                if code.is_none() {
+                    self.peek(0)?;
                 }
-                GotoEqual => {
-                    let new_line = self.pop()?;
-                    let sentinal = self.pop()?;
-                    if sentinal == 0 {
-                        self.curr_instruction_idx = new_line as usize - 1;
-                    }
+                self.exit_code = code;
+                self.curr_instruction_idx = self.program.len();
+                return Ok(());
+            }
+            Enter(params, size) => {
+                if params > 0 {
+                    self.peek(params - 1)?;
                 }
-                RePush => self.stack.push(self.last()?),
-                ClearStack => self.stack = vec![],
-                Add => {
-                    let arg1 = self.pop()?;
-                    let arg2 = self.pop()?;
-                    self.stack.push(arg1 + arg2);
+                let frame_pointer = self.locals.len();
+                let frame_end = frame_pointer + size.max(params);
+                // frames come out of the same budget as memory
+                if frame_end > self.memory.max_size() {
+                    return Err(self.bad_address(frame_end as i64));
                 }
-                Sub => {
-                    let arg1 = self.pop()?;
-                    let arg2 = self.pop()?;
-                    self.stack.push(arg1 - arg2);
+                self.locals.resize(frame_end, 0);
+                for slot in (0..params).rev() {
+                    self.locals[frame_pointer + slot] = self.pop()?;
                 }
-                Mul => {
-                    let arg1 = self.pop()?;
-                    let arg2 = self.pop()?;
-                    self.stack.push(arg1 * arg2);
+                self.frame_pointer = frame_pointer;
+            }
+            LoadLocal(slot) => {
+                let idx = self.local_idx(slot)?;
+                self.stack.push(self.locals[idx]);
+            }
+            StoreLocal(slot) => {
+                let idx = self.local_idx(slot)?;
+                self.locals[idx] = self.pop()?;
+            }
+            RePush => self.stack.push(self.last()?),
+            ClearStack => self.stack = vec![],
+            Add => self.binary_op(|vm, arg1, arg2| {
+                vm.arithmetic_result(
+                    arg1.checked_add(arg2),
+                    arg1.wrapping_add(arg2),
+                    arg1.saturating_add(arg2),
+                )
+            })?,
+            Sub => self.binary_op(|vm, arg1, arg2| {
+                vm.arithmetic_result(
+                    arg1.checked_sub(arg2),
+                    arg1.wrapping_sub(arg2),
+                    arg1.saturating_sub(arg2),
+                )
+            })?,
+            Mul => self.binary_op(|vm, arg1, arg2| {
+                vm.arithmetic_result(
+                    arg1.checked_mul(arg2),
+                    arg1.wrapping_mul(arg2),
+                    arg1.saturating_mul(arg2),
+                )
+            })?,
+            Div => self.binary_op(|vm, arg1, arg2| {
+                if arg2 == 0 {
+                    return Err(ExecError::DivideByZero(vm.snapshot()));
+                }
+                vm.arithmetic_result(
+                    arg1.checked_div(arg2),
+                    arg1.wrapping_div(arg2),
+                    arg1.saturating_div(arg2),
+                )
+            })?,
+            Mod => self.binary_op(|vm, arg1, arg2| {
+                if arg2 == 0 {
+                    return Err(ExecError::DivideByZero(vm.snapshot()));
                 }
-                Div => {
-                    let arg1 = self.pop()?;
-                    let arg2 = self.pop()?;
-                    self.stack.push(arg1 / arg2);
+                // the only overflowing case is MIN % -1 whose true
+                // result 0 is also the wrapped one
+                vm.arithmetic_result(
+                    arg1.checked_rem(arg2),
+                    arg1.wrapping_rem(arg2),
+                    arg1.wrapping_rem(arg2),
+                )
+            })?,
+            Neg => {
+                let arg = self.peek(0)?;
+                let result = self.arithmetic_result(
+                    arg.checked_neg(),
+                    arg.wrapping_neg(),
+                    arg.saturating_neg(),
+                )?;
+                self.pop()?;
+                self.stack.push(result);
+            }
+            Eq | Ne | Lt | Gt | Le | Ge => {
+                self.binary_op(|_, arg1, arg2| Ok(compare(&instruction, arg1, arg2) as i64))?
+            }
+            NoOp => (),
+        }
+        self.curr_instruction_idx += 1;
+        Ok(())
+    }
+    // picks the result for the current arithmetic mode given what each
+    // flavour of the operation produced
+    fn arithmetic_result(
+        &self,
+        checked: Option<i64>,
+        wrapped: i64,
+        saturated: i64,
+    ) -> Result<i64, ExecError> {
+        match self.arithmetic {
+            ArithmeticMode::Trap => checked.ok_or_else(|| ExecError::Overflow(self.snapshot())),
+            ArithmeticMode::Wrapping => Ok(wrapped),
+            ArithmeticMode::Saturating => Ok(saturated),
+        }
+    }
+    // replaces the top two values of the stack with the result of op which
+    // gets the top of the stack as its first argument
+    fn binary_op<F>(&mut self, op: F) -> Result<(), ExecError>
+    where
+        F: Fn(&Self, i64, i64) -> Result<i64, ExecError>,
+    {
+        let arg1 = self.peek(0)?;
+        let arg2 = self.peek(1)?;
+        let result = op(self, arg1, arg2)?;
+        self.stack.truncate(self.stack.len() - 2);
+        self.stack.push(result);
+        Ok(())
+    }
+    // where a jump goes and how many values it pops to find out, failing
+    // if it lands anywhere execution cannot continue from
+    fn jump_dest(&self, target: Target) -> Result<(usize, usize), ExecError> {
+        match target {
+            Target::Addr(addr) => {
+                self.check_jump(addr as i64)?;
+                Ok((addr, 0))
+            }
+            Target::Stack => {
+                let new_line = self.peek(0)?;
+                self.check_jump(new_line)?;
+                let unexpected = match &self.jump_targets {
+                    Some(targets) => !targets.contains(&(new_line as usize)),
+                    None => false,
+                };
+                if unexpected {
+                    return Err(ExecError::UnexpectedJumpTarget {
+                        target: new_line,
+                        at: self.snapshot(),
+                    });
                 }
-                NoOp => (),
+                Ok((new_line as usize, 1))
             }
-            self.curr_instruction_idx += 1;
         }
-        return self.pop();
+    }
+    fn check_jump(&self, new_line: i64) -> Result<(), ExecError> {
+        if new_line < 0 || new_line as usize > self.program.len() {
+            return Err(ExecError::BadJumpTarget {
+                target: new_line,
+                at: self.snapshot(),
+            });
+        }
+        Ok(())
     }
     pub fn new() -> Self {
-        return Self {
+        Self {
             stack: Vec::new(),
-            vars: Vec::new(),
+            memory: Memory::default(),
             curr_instruction_idx: 0,
-        };
+            call_stack: Vec::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            locals: Vec::new(),
+            frame_pointer: 0,
+            arithmetic: ArithmeticMode::Trap,
+            fuel: None,
+            source_map: None,
+            jump_targets: None,
+            exit_code: None,
+            program: Vec::new(),
+        }
+    }
+}
+
+// decides whether a conditional jump is taken given the sentinal it popped
+pub(crate) fn branch_taken(instruction: &Instruction, sentinal: i64) -> bool {
+    match instruction {
+        GotoEqual(_) => sentinal == 0,
+        GotoNotEqual(_) => sentinal != 0,
+        GotoLess(_) => sentinal < 0,
+        GotoGreater(_) => sentinal > 0,
+        GotoLessEqual(_) => sentinal <= 0,
+        GotoGreaterEqual(_) => sentinal >= 0,
+        _ => panic!("branch_taken called with non conditional jump"),
+    }
+}
+
+// evaluates a comparison instruction where arg1 was the top of the stack
+pub(crate) fn compare(instruction: &Instruction, arg1: i64, arg2: i64) -> bool {
+    match instruction {
+        Eq => arg1 == arg2,
+        Ne => arg1 != arg2,
+        Lt => arg1 < arg2,
+        Gt => arg1 > arg2,
+        Le => arg1 <= arg2,
+        Ge => arg1 >= arg2,
+        _ => panic!("compare called with non comparison instruction"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(program: Vec<Instruction>) -> Result<i64, ExecError> {
+        Interpreter::new().run(program, false)
+    }
+
+    fn run_with(mode: ArithmeticMode, program: Vec<Instruction>) -> Result<i64, ExecError> {
+        let mut vm = Interpreter::new();
+        vm.arithmetic = mode;
+        vm.run(program, false)
+    }
+
+    // i64::MIN / -1 with i64::MIN on top of the stack
+    fn min_by_minus_one(op: Instruction) -> Vec<Instruction> {
+        vec![Push(-1), Push(i64::MIN), op]
+    }
+
+    #[test]
+    fn test_trap_mode() {
+        for op in [Div, Mod, Mul] {
+            let res = run_with(ArithmeticMode::Trap, min_by_minus_one(op));
+            assert!(matches!(res, Err(ExecError::Overflow(_))));
+        }
+        let res = run_with(ArithmeticMode::Trap, vec![Push(i64::MIN), Neg]);
+        assert!(matches!(res, Err(ExecError::Overflow(_))));
+    }
+
+    #[test]
+    fn test_wrapping_mode() {
+        let mode = ArithmeticMode::Wrapping;
+        assert_eq!(run_with(mode, min_by_minus_one(Div)), Ok(i64::MIN));
+        assert_eq!(run_with(mode, min_by_minus_one(Mod)), Ok(0));
+        assert_eq!(run_with(mode, min_by_minus_one(Mul)), Ok(i64::MIN));
+        assert_eq!(run_with(mode, vec![Push(i64::MIN), Neg]), Ok(i64::MIN));
+        assert_eq!(
+            run_with(mode, vec![Push(1), Push(i64::MAX), Add]),
+            Ok(i64::MIN)
+        );
+    }
+
+    #[test]
+    fn test_saturating_mode() {
+        let mode = ArithmeticMode::Saturating;
+        assert_eq!(run_with(mode, min_by_minus_one(Div)), Ok(i64::MAX));
+        assert_eq!(run_with(mode, min_by_minus_one(Mod)), Ok(0));
+        assert_eq!(run_with(mode, min_by_minus_one(Mul)), Ok(i64::MAX));
+        assert_eq!(run_with(mode, vec![Push(i64::MIN), Neg]), Ok(i64::MAX));
+        assert_eq!(
+            run_with(mode, vec![Push(1), Push(i64::MIN), Sub]),
+            Ok(i64::MIN)
+        );
+    }
+
+    #[test]
+    fn test_mod_and_neg() {
+        assert_eq!(run(vec![Push(3), Push(-7), Mod]), Ok(-1));
+        assert_eq!(run(vec![Push(5), Neg]), Ok(-5));
+        for mode in [ArithmeticMode::Wrapping, ArithmeticMode::Saturating] {
+            let res = run_with(mode, vec![Push(0), Push(1), Mod]);
+            assert!(matches!(res, Err(ExecError::DivideByZero(_))));
+        }
+    }
+
+    #[test]
+    fn test_step() {
+        let mut vm = Interpreter::new();
+        vm.load(vec![Push(2), Push(3), Add]);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.stack, vec![2]);
+        assert_eq!(vm.run_for(1), StepResult::Continue);
+        assert_eq!(vm.curr_instruction_idx, 2);
+        assert_eq!(vm.run_for(10), StepResult::Halted(5));
+        assert_eq!(vm.step(), StepResult::Halted(5));
+        assert_eq!(vm.stack, vec![5]);
+    }
+
+    #[test]
+    fn test_halt() {
+        assert_eq!(run(vec![Push(4), Halt(None), Push(5)]), Ok(4));
+        let mut vm = Interpreter::new();
+        vm.load(vec![Push(4), Halt(Some(-2)), Push(5)]);
+        assert_eq!(vm.run_for(10), StepResult::Halted(-2));
+        assert_eq!(vm.step(), StepResult::Halted(-2));
+        assert_eq!(vm.stack, vec![4]);
+        let err = run(vec![Halt(None), Push(5)]).unwrap_err();
+        assert_eq!(err.snapshot().unwrap().instruction, Some(Halt(None)));
+    }
+
+    #[test]
+    fn test_step_error() {
+        let mut vm = Interpreter::new();
+        vm.load(vec![Push(1), Add, Push(2)]);
+        let res = vm.run_for(10);
+        assert!(matches!(
+            res,
+            StepResult::Error(ExecError::StackUnderflow(_))
+        ));
+        assert_eq!(vm.curr_instruction_idx, 1);
+    }
+
+    #[test]
+    fn test_out_of_fuel() {
+        // an infinite loop jumping back to itself
+        let mut vm = Interpreter::new();
+        vm.fuel = Some(4);
+        let err = vm
+            .run(vec![NoOp, Goto(Target::Addr(1))], false)
+            .unwrap_err();
+        match err {
+            ExecError::OutOfFuel(at) => assert_eq!(at.instruction, Some(Goto(Target::Addr(1)))),
+            err => panic!("unexpected error {:?}", err),
+        }
+        let mut vm = Interpreter::new();
+        vm.fuel = Some(3);
+        assert_eq!(vm.run(vec![Push(1), Push(2), Add], false), Ok(3));
+    }
+
+    #[test]
+    fn test_stack_underflow() {
+        let err = run(vec![Push(1), Add]).unwrap_err();
+        assert_eq!(
+            err,
+            ExecError::StackUnderflow(Box::new(Snapshot {
+                instruction_idx: 1,
+                instruction: Some(Add),
+                stack_top: vec![1],
+                location: None,
+            }))
+        );
+    }
+
+    #[test]
+    fn test_divide_by_zero() {
+        let err = run(vec![Push(0), Push(5), Div]).unwrap_err();
+        assert!(matches!(err, ExecError::DivideByZero(_)));
+        assert_eq!(err.snapshot().unwrap().stack_top, vec![0, 5]);
+    }
+
+    #[test]
+    fn test_overflow() {
+        let err = run(vec![Push(1), Push(i64::MAX), Add]).unwrap_err();
+        assert!(matches!(err, ExecError::Overflow(_)));
+    }
+
+    #[test]
+    fn test_bad_address() {
+        let err = run(vec![Push(-1), Load]).unwrap_err();
+        assert!(matches!(err, ExecError::BadAddress { address: -1, .. }));
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut vm = Interpreter::new();
+        vm.memory = Memory::new(4);
+        assert_eq!(
+            vm.run(vec![Push(9), Push(3), Store, Push(3), Load], false),
+            Ok(9)
+        );
+        let mut vm = Interpreter::new();
+        vm.memory = Memory::new(4);
+        let err = vm.run(vec![Push(9), Push(4), Store], false).unwrap_err();
+        assert!(matches!(err, ExecError::BadAddress { address: 4, .. }));
+        let err = run(vec![Push(i64::MAX), Load]).unwrap_err();
+        assert!(matches!(err, ExecError::BadAddress { .. }));
+    }
+
+    #[test]
+    fn test_call_and_return() {
+        // the callee doubles its argument on the caller's stack
+        let program = vec![
+            Push(4),
+            Push(21),
+            Call(5),
+            Add,
+            Goto(Target::Addr(8)),
+            RePush,
+            Add,
+            Ret,
+        ];
+        assert_eq!(run(program), Ok(46));
+        let err = run(vec![Push(1), Ret]).unwrap_err();
+        assert!(matches!(err, ExecError::ReturnWithoutCall(_)));
+    }
+
+    #[test]
+    fn test_frames() {
+        // sums 0..=n recursively, reading n back after the recursive call
+        // so every activation needs its own slot
+        let program = vec![
+            Push(3),
+            Call(3),
+            Goto(Target::Addr(15)),
+            Enter(1, 1),
+            LoadLocal(0),
+            GotoEqual(Target::Addr(13)),
+            LoadLocal(0),
+            Push(-1),
+            Add,
+            Call(3),
+            LoadLocal(0),
+            Add,
+            Ret,
+            Push(0),
+            Ret,
+        ];
+        let mut vm = Interpreter::new();
+        assert_eq!(vm.run(program, false), Ok(6));
+        assert!(vm.locals.is_empty());
+        let err = run(vec![LoadLocal(0)]).unwrap_err();
+        assert!(matches!(err, ExecError::BadAddress { .. }));
+    }
+
+    #[test]
+    fn test_call_depth() {
+        let mut vm = Interpreter::new();
+        vm.max_call_depth = 8;
+        let err = vm.run(vec![NoOp, Call(1)], false).unwrap_err();
+        assert!(matches!(err, ExecError::CallDepthExceeded(_)));
+        assert_eq!(vm.call_stack.len(), 8);
+    }
+
+    #[test]
+    fn test_bad_jump_target() {
+        let err = run(vec![Goto(Target::Addr(7))]).unwrap_err();
+        assert!(matches!(err, ExecError::BadJumpTarget { target: 7, .. }));
+        let err = run(vec![Push(-1), Goto(Target::Stack)]).unwrap_err();
+        assert!(matches!(err, ExecError::BadJumpTarget { target: -1, .. }));
+    }
+
+    #[test]
+    fn test_jump_to_start() {
+        // counts down from 3 by jumping back to the first instruction
+        let program = vec![
+            Push(-1),
+            Add,
+            RePush,
+            GotoEqual(Target::Addr(6)),
+            Push(0),
+            Goto(Target::Stack),
+        ];
+        let mut vm = Interpreter::new();
+        vm.stack.push(3);
+        assert_eq!(vm.run(program, false), Ok(0));
+    }
+
+    #[test]
+    fn test_unexpected_jump_target() {
+        let program = vec![Push(2), Goto(Target::Stack), NoOp, Push(1)];
+        let mut vm = Interpreter::new();
+        vm.jump_targets = Some(BTreeSet::from([3]));
+        let err = vm.run(program.clone(), false).unwrap_err();
+        assert!(matches!(
+            err,
+            ExecError::UnexpectedJumpTarget { target: 2, .. }
+        ));
+        assert_eq!(vm.stack, vec![2]);
+        vm.stack.clear();
+        vm.jump_targets = Some(BTreeSet::from([2]));
+        assert_eq!(vm.run(program, false), Ok(1));
+    }
+
+    #[test]
+    fn test_empty_stack_at_end() {
+        let err = run(vec![NoOp]).unwrap_err();
+        assert_eq!(err.snapshot().unwrap().instruction, None);
     }
 }
@@ -2,20 +2,89 @@
 // Use of this source code is governed by the MIT License which can be
 // found in the LICENSE file.
 
+pub mod bytecode;
 pub mod codegen;
+pub mod debugger;
+pub mod disasm;
 pub mod interpreter;
+pub mod linker;
+pub mod macros;
+pub mod memory;
+pub mod optimizer;
 pub mod parser;
+pub mod repl;
 pub mod types;
+pub mod verify;
 use crate::interpreter::Interpreter;
-use crate::types::ExecError;
-use std::{fs, path::PathBuf};
-
-pub fn run_file(path: &PathBuf, debug: bool) -> Result<i64, ExecError> {
-    let file = fs::read_to_string(path).expect("problem reading file");
-    let parsed = parser::code(file.as_str()).expect("parser error").1;
-    let mut compiler = codegen::CodeGen::new();
-    let lowered = compiler.lower_IR(&parsed)?;
-    println!("{:?}", lowered);
+use crate::memory::DEFAULT_MEMORY_SIZE;
+use crate::parser::ParseOptions;
+use crate::types::{ExecError, Program};
+use std::{fs, path::Path};
+
+/// The extension of compiled bytecode files
+pub const BYTECODE_EXTENSION: &str = "spdc";
+
+/// How programs are compiled and run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Options {
+    // print the disassembly and a trace of every instruction executed
+    pub debug: bool,
+    // fail with ExecError::OutOfFuel once this many instructions executed
+    pub max_steps: Option<u64>,
+    // refuse to run programs the verifier finds errors in
+    pub verify: bool,
+    // run the optimizer over programs compiled from source
+    pub optimize: bool,
+    pub parse: ParseOptions,
+}
+
+pub fn run_file(path: &Path, options: &Options) -> Result<i64, ExecError> {
+    let program = load_file(path, options)?;
+    if options.debug {
+        print!("{}", disasm::disassemble(&program));
+    }
+    run_program(program, options)
+}
+
+pub fn run_program(program: Program, options: &Options) -> Result<i64, ExecError> {
+    if options.verify {
+        let errors: Vec<_> = verify::verify_program(&program)
+            .into_iter()
+            .filter(verify::Diagnostic::is_error)
+            .collect();
+        if !errors.is_empty() {
+            return Err(ExecError::Verify(errors));
+        }
+    }
     let mut vm = Interpreter::new();
-    vm.run(lowered, debug)
+    vm.fuel = options.max_steps;
+    vm.source_map = program.debug.map(|info| info.source_map);
+    vm.jump_targets = program.jump_targets;
+    vm.run(program.instructions, options.debug)
+}
+
+/// Reads a program from either a source file or a compiled .spdc file
+pub fn load_file(path: &Path, options: &Options) -> Result<Program, ExecError> {
+    if path
+        .extension()
+        .is_some_and(|ext| ext == BYTECODE_EXTENSION)
+    {
+        let bytes = fs::read(path).map_err(|err| io_error(path, err))?;
+        return bytecode::decode(&bytes);
+    }
+    compile_file(path, options)
+}
+
+/// Compiles a source file and every file it imports
+pub fn compile_file(path: &Path, options: &Options) -> Result<Program, ExecError> {
+    let program = linker::link(path, options.parse)?;
+    if options.optimize {
+        // run_program runs it with the default memory
+        return Ok(optimizer::optimize(program, DEFAULT_MEMORY_SIZE));
+    }
+    Ok(program)
+}
+
+pub(crate) fn io_error(path: &Path, err: std::io::Error) -> ExecError {
+    ExecError::Io(format!("{}: {}", path.display(), err))
 }
@@ -0,0 +1,170 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+//! Lowers a source file along with every file it imports into one program.
+//!
+//! An import is lowered in place the first time it is reached, so the
+//! module's top level code runs at that point, and later imports of the same
+//! file are skipped. The labels and globals of a module are put in a
+//! namespace named after its file, `import "lib/math.spd"` makes `multiply`
+//! available as `math::multiply`. Two different files cannot share a
+//! namespace.
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::codegen::CodeGen;
+use crate::io_error;
+use crate::macros;
+use crate::parser::{self, ParseOptions, Statement};
+use crate::types::{DebugInfo, ExecError, Program, SourceFile, SourceMap};
+
+pub fn link(path: &Path, options: ParseOptions) -> Result<Program, ExecError> {
+    let mut compiler = CodeGen::new();
+    let mut files = Vec::new();
+    Linker::default().lower_file(&mut compiler, &mut files, path, options)?;
+    let instructions = compiler.resolve()?;
+    let debug = DebugInfo {
+        source_map: SourceMap {
+            files,
+            instruction_files: compiler.instruction_files().to_vec(),
+            instruction_lines: compiler.instruction_lines().to_vec(),
+        },
+        labels: compiler.labels().clone().into_iter().collect(),
+        variables: compiler.variable_names().clone().into_iter().collect(),
+        frames: compiler.frames().clone(),
+    };
+    Ok(Program {
+        instructions,
+        debug: Some(debug),
+        jump_targets: Some(compiler.jump_targets()),
+    })
+}
+
+/// Lowers files into a compiler, remembering what they imported so later
+/// files importing the same modules do not lower them again
+#[derive(Clone, Default)]
+pub struct Linker {
+    // canonical path and name of the files being lowered, the innermost
+    // import last
+    importing: Vec<(PathBuf, String)>,
+    imported: HashSet<PathBuf>,
+    // the canonical path and name of the file each namespace was given to
+    namespaces: HashMap<String, (PathBuf, String)>,
+}
+
+impl Linker {
+    /// Lowers a file and everything it imports into the compiler without
+    /// resolving it. The files are added to files, which the file indexes
+    /// given to the compiler refer to, and the compiler is left lowering
+    /// file 0 outside any namespace.
+    pub fn lower_file(
+        &mut self,
+        compiler: &mut CodeGen,
+        files: &mut Vec<SourceFile>,
+        path: &Path,
+        options: ParseOptions,
+    ) -> Result<(), ExecError> {
+        let lowered = self.lower_module(compiler, files, path, None, options);
+        compiler.set_source(0, None, None);
+        lowered
+    }
+
+    fn lower_module(
+        &mut self,
+        compiler: &mut CodeGen,
+        files: &mut Vec<SourceFile>,
+        path: &Path,
+        namespace: Option<&str>,
+        options: ParseOptions,
+    ) -> Result<(), ExecError> {
+        let name = path.display().to_string();
+        let canonical = fs::canonicalize(path).map_err(|err| io_error(path, err))?;
+        let importing = self
+            .importing
+            .iter()
+            .position(|(file, _)| *file == canonical);
+        if let Some(start) = importing {
+            let mut cycle: Vec<&str> = self.importing[start..]
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .collect();
+            cycle.push(&name);
+            return Err(ExecError::Import(format!(
+                "import cycle {}",
+                cycle.join(" -> ")
+            )));
+        }
+        if let Some(namespace) = namespace {
+            match self.namespaces.get(namespace) {
+                Some((file, other)) if *file != canonical => {
+                    return Err(ExecError::Import(format!(
+                        "{} and {} both use the namespace {}",
+                        other, name, namespace
+                    )))
+                }
+                Some(_) => (),
+                None => {
+                    self.namespaces
+                        .insert(namespace.to_string(), (canonical.clone(), name.clone()));
+                }
+            }
+        }
+        if !self.imported.insert(canonical.clone()) {
+            return Ok(());
+        }
+        let source = fs::read_to_string(path).map_err(|err| io_error(path, err))?;
+        let expansion = macros::expand(&source, options)?;
+        let code = parser::parse_with(&expansion.text, options).map_err(|err| {
+            let mut err = expansion.locate(&source, err);
+            if namespace.is_some() {
+                err.file = Some(name.clone());
+            }
+            ExecError::from(err)
+        })?;
+        let file = files.len();
+        // errors in the file being compiled are reported without its name
+        // like parse errors are
+        let module_name = namespace.map(|_| name.as_str());
+        files.push(SourceFile::new(&name, &source));
+        self.importing.push((canonical, name.clone()));
+        compiler.set_source(file, module_name, namespace);
+        for (stmt, span) in code.lines.iter().zip(code.spans.iter()) {
+            let line = expansion.source_line(span.line);
+            let import = match stmt {
+                Statement::Import(import) => import,
+                stmt => {
+                    let note = expansion.note(span.line);
+                    compiler.lower_expanded_line(stmt, line, note)?;
+                    continue;
+                }
+            };
+            if compiler.in_block() {
+                return Err(ExecError::BlockMismatch {
+                    file: module_name.map(str::to_string),
+                    line,
+                    details: format!("import of {} inside a block", import),
+                    note: None,
+                });
+            }
+            let import_path = path.parent().unwrap_or_else(|| Path::new("")).join(import);
+            let module = match import_path.file_stem() {
+                Some(stem) => stem.to_string_lossy().to_string(),
+                None => return Err(ExecError::Import(format!("{} is not a file", import))),
+            };
+            self.lower_module(compiler, files, &import_path, Some(&module), options)?;
+            compiler.set_source(file, module_name, namespace);
+        }
+        if namespace.is_some() && compiler.in_block() {
+            return Err(ExecError::BlockMismatch {
+                file: Some(name.clone()),
+                line: source.lines().count(),
+                details: format!("{} ends inside a block", name),
+                note: None,
+            });
+        }
+        self.importing.pop();
+        Ok(())
+    }
+}
@@ -0,0 +1,415 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+//! Expands macros in source text before it is parsed.
+//!
+//! ```text
+//! macro loadAt(addr)
+//! push addr
+//! load
+//! endmacro
+//! loadAt(2)
+//! ```
+//!
+//! A call is replaced by the body of the macro with every parameter replaced
+//! by the text of its argument. Labels defined inside a body are renamed for
+//! each expansion so a macro holding a loop can be used more than once.
+use std::collections::{HashMap, HashSet};
+
+use crate::parser::ParseOptions;
+use crate::types::{ExecError, ParseError};
+
+// how deep macros may call other macros, which stops a macro that calls
+// itself
+const MAX_EXPANSION_DEPTH: usize = 64;
+
+/// Source text with every macro expanded, along with where each of its
+/// lines came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    pub text: String,
+    origins: Vec<Origin>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Origin {
+    // 1 based line in the source, the call site for expanded lines
+    line: usize,
+    // the macro and the line of its body an expanded line came from,
+    // written when the line is emitted
+    note: Option<Box<str>>,
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    line: usize,
+    params: Vec<String>,
+    // the source line and text of every line between macro and endmacro
+    body: Vec<(usize, String)>,
+}
+
+pub fn expand(source: &str, options: ParseOptions) -> Result<Expansion, ExecError> {
+    let mut expander = Expander {
+        ignore_case: options.ignore_case,
+        macros: HashMap::new(),
+        words: words(source).map(str::to_string).collect(),
+        expansion_count: 0,
+        text: String::new(),
+        origins: Vec::new(),
+    };
+    let mut lines = source.lines().zip(1..);
+    while let Some((text, line)) = lines.next() {
+        let code = strip_comment(text).trim();
+        if expander.is_keyword(code, "endmacro") {
+            return Err(ExecError::Macro {
+                line,
+                details: "endmacro without a matching macro".to_string(),
+            });
+        }
+        if !expander.is_keyword(code, "macro") {
+            expander.emit(text, line, None, 0)?;
+            continue;
+        }
+        let (name, params) = parse_header(code, line)?;
+        let mut body = Vec::new();
+        loop {
+            match lines.next() {
+                Some((text, body_line)) => {
+                    let code = strip_comment(text).trim();
+                    if expander.is_keyword(code, "endmacro") {
+                        break;
+                    }
+                    if expander.is_keyword(code, "macro") {
+                        return Err(ExecError::Macro {
+                            line: body_line,
+                            details: format!("macro defined inside macro {}", name),
+                        });
+                    }
+                    body.push((body_line, text.to_string()));
+                }
+                None => {
+                    return Err(ExecError::Macro {
+                        line,
+                        details: format!("macro {} is missing its endmacro", name),
+                    })
+                }
+            }
+        }
+        // removed lines still count so later lines keep their numbers
+        expander.macros.insert(name, Macro { line, params, body });
+    }
+    Ok(Expansion {
+        text: expander.text,
+        origins: expander.origins,
+    })
+}
+
+impl Expansion {
+    /// The source line an expanded line came from, for expanded macro
+    /// bodies that is the line of the call
+    pub fn source_line(&self, line: usize) -> usize {
+        match self.origins.get(line.wrapping_sub(1)) {
+            Some(origin) => origin.line,
+            None => line,
+        }
+    }
+
+    /// Where in a macro an expanded line came from, None for lines that
+    /// are not part of an expanded macro body
+    pub fn note(&self, line: usize) -> Option<Box<str>> {
+        self.origins.get(line.wrapping_sub(1))?.note.clone()
+    }
+
+    /// Moves a parse error in the expanded text to the source, an error
+    /// inside an expanded macro points at the call and notes the line of
+    /// the macro it came from
+    pub fn locate(&self, source: &str, mut err: ParseError) -> ParseError {
+        let origin = match self.origins.get(err.line.wrapping_sub(1)) {
+            Some(origin) => origin,
+            None => return err,
+        };
+        let note = self.note(err.line);
+        err.line = origin.line;
+        if note.is_some() {
+            err.note = note;
+            let call = source.lines().nth(origin.line - 1).unwrap_or("");
+            err.column = call.len() - call.trim_start().len() + 1;
+            err.snippet = call.trim_end().to_string();
+        }
+        err
+    }
+}
+
+struct Expander {
+    ignore_case: bool,
+    macros: HashMap<String, Macro>,
+    // every word in the source, generated labels must not be one of them
+    words: HashSet<String>,
+    expansion_count: usize,
+    text: String,
+    origins: Vec<Origin>,
+}
+
+impl Expander {
+    fn is_keyword(&self, code: &str, keyword: &str) -> bool {
+        let word = code.split_whitespace().next().unwrap_or("");
+        let word = word.split('(').next().unwrap_or("");
+        match self.ignore_case {
+            true => word.eq_ignore_ascii_case(keyword),
+            false => word == keyword,
+        }
+    }
+
+    // adds a line to the expanded text, expanding it first if it calls a
+    // macro
+    fn emit(
+        &mut self,
+        text: &str,
+        line: usize,
+        expanded_from: Option<(String, usize)>,
+        depth: usize,
+    ) -> Result<(), ExecError> {
+        let call = match self.call(text) {
+            Some(call) => call,
+            None => {
+                self.text.push_str(text);
+                self.text.push('\n');
+                let note = expanded_from.map(|(name, body_line)| {
+                    format!("in macro {} at line {}: {}", name, body_line, text.trim()).into()
+                });
+                self.origins.push(Origin { line, note });
+                return Ok(());
+            }
+        };
+        let (label, name, args) = call;
+        let definition = self.macros[&name].clone();
+        if depth >= MAX_EXPANSION_DEPTH {
+            return Err(ExecError::Macro {
+                line,
+                details: format!(
+                    "macro {} defined at line {} expands itself too deeply",
+                    name, definition.line
+                ),
+            });
+        }
+        if args.len() != definition.params.len() {
+            return Err(ExecError::Macro {
+                line,
+                details: format!(
+                    "macro {} defined at line {} takes {} arguments but {} were given",
+                    name,
+                    definition.line,
+                    definition.params.len(),
+                    args.len()
+                ),
+            });
+        }
+        if let Some(label) = label {
+            // a label on a call marks where the expansion starts
+            self.emit(&format!("!![{}] noOp", label), line, expanded_from, depth)?;
+        }
+        let mut renames: HashMap<String, String> =
+            definition.params.iter().cloned().zip(args).collect();
+        self.expansion_count += 1;
+        for (_, body_text) in definition.body.iter() {
+            if let Some(label) = defined_label(body_text) {
+                let fresh = self.fresh_label(label);
+                renames.insert(label.to_string(), fresh);
+            }
+        }
+        for (body_line, body_text) in definition.body.iter() {
+            let expanded = substitute(body_text, &renames);
+            let from = Some((name.clone(), *body_line));
+            self.emit(&expanded, line, from, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    // the label, macro name and arguments if the line calls a known macro
+    fn call(&self, text: &str) -> Option<(Option<String>, String, Vec<String>)> {
+        let mut code = strip_comment(text).trim();
+        let mut label = None;
+        if let Some(labeled) = code.strip_prefix("!![") {
+            let end = labeled.find(']')?;
+            label = Some(labeled[..end].to_string());
+            code = labeled[end + 1..].trim_start();
+        }
+        let open = code.find('(')?;
+        let name = code[..open].trim_end();
+        if !self.macros.contains_key(name) || !code.ends_with(')') {
+            return None;
+        }
+        let args = split_args(&code[open + 1..code.len() - 1]);
+        Some((label, name.to_string(), args))
+    }
+
+    fn fresh_label(&mut self, label: &str) -> String {
+        loop {
+            let fresh = format!("{}M{}", label, self.expansion_count);
+            if !self.words.contains(&fresh) {
+                self.words.insert(fresh.clone());
+                return fresh;
+            }
+            self.expansion_count += 1;
+        }
+    }
+}
+
+// `macro name(a, b)` into the name and parameters
+fn parse_header(code: &str, line: usize) -> Result<(String, Vec<String>), ExecError> {
+    let bad_header = || ExecError::Macro {
+        line,
+        details: "expected macro <name>(<param>, ...)".to_string(),
+    };
+    let header = code["macro".len()..].trim();
+    let open = header.find('(').ok_or_else(bad_header)?;
+    let name = header[..open].trim_end();
+    if !header.ends_with(')') || !is_name(name) {
+        return Err(bad_header());
+    }
+    let params = split_args(&header[open + 1..header.len() - 1]);
+    if !params.iter().all(|param| is_name(param)) {
+        return Err(bad_header());
+    }
+    Ok((name.to_string(), params))
+}
+
+fn is_name(word: &str) -> bool {
+    !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+// splits on commas outside of parentheses so arguments can be expressions
+fn split_args(args: &str) -> Vec<String> {
+    if args.trim().is_empty() {
+        return Vec::new();
+    }
+    let mut split = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (idx, c) in args.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(args[start..idx].trim().to_string());
+                start = idx + 1;
+            }
+            _ => (),
+        }
+    }
+    split.push(args[start..].trim().to_string());
+    split
+}
+
+fn strip_comment(text: &str) -> &str {
+    match text.find("//") {
+        Some(idx) => &text[..idx],
+        None => text,
+    }
+}
+
+fn defined_label(text: &str) -> Option<&str> {
+    let labeled = text.trim_start().strip_prefix("!![")?;
+    Some(&labeled[..labeled.find(']')?])
+}
+
+fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty())
+}
+
+// replaces whole words of the code part of a line, leaving its comment alone
+fn substitute(text: &str, renames: &HashMap<String, String>) -> String {
+    let code = strip_comment(text);
+    let mut out = String::new();
+    let mut word_start = None;
+    for (idx, c) in code
+        .char_indices()
+        .chain(std::iter::once((code.len(), ' ')))
+    {
+        if c.is_ascii_alphanumeric() {
+            word_start.get_or_insert(idx);
+            continue;
+        }
+        if let Some(start) = word_start.take() {
+            let word = &code[start..idx];
+            out.push_str(renames.get(word).map(String::as_str).unwrap_or(word));
+        }
+        if idx < code.len() {
+            out.push(c);
+        }
+    }
+    out.push_str(&text[code.len()..]);
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser;
+
+    fn expand_text(source: &str) -> Result<String, ExecError> {
+        expand(source, ParseOptions::default()).map(|expansion| expansion.text)
+    }
+
+    #[test]
+    fn test_expand() {
+        let source = "macro loadAt(addr)\npush addr // addr stays\nload\nendmacro\nloadAt(2)\n  loadAt( (1 + 2) )";
+        assert_eq!(
+            expand_text(source).unwrap(),
+            "push 2 // addr stays\nload\npush (1 + 2) // addr stays\nload\n"
+        );
+        let expansion = expand(source, ParseOptions::default()).unwrap();
+        assert_eq!(expansion.source_line(1), 5);
+        assert_eq!(expansion.source_line(4), 6);
+    }
+
+    #[test]
+    fn test_hygiene() {
+        let source = "macro spin(n)\n!![top] push n\ngotoEqual top\nendmacro\n!![a] spin(0)\nspin(1)\n!![topM1] noOp";
+        assert_eq!(
+            expand_text(source).unwrap(),
+            "!![a] noOp\n!![topM2] push 0\ngotoEqual topM2\n!![topM3] push 1\ngotoEqual topM3\n!![topM1] noOp\n"
+        );
+    }
+
+    #[test]
+    fn test_nested_calls() {
+        let source =
+            "macro two()\npush 2\nendmacro\nmacro four()\ntwo()\ntwo()\nadd\nendmacro\nfour()";
+        assert_eq!(expand_text(source).unwrap(), "push 2\npush 2\nadd\n");
+        let source = "macro loop()\nloop()\nendmacro\nloop()";
+        let err = expand_text(source).unwrap_err();
+        assert!(matches!(err, ExecError::Macro { line: 4, .. }), "{}", err);
+    }
+
+    #[test]
+    fn test_errors() {
+        let err = expand_text("macro f(a)\npush a\nendmacro\nf(1, 2)").unwrap_err();
+        assert_eq!(
+            err,
+            ExecError::Macro {
+                line: 4,
+                details: "macro f defined at line 1 takes 1 arguments but 2 were given".to_string()
+            }
+        );
+        let err = expand_text("push 1\nmacro f(a)\npush a").unwrap_err();
+        assert!(matches!(err, ExecError::Macro { line: 2, .. }));
+        let err = expand_text("endmacro").unwrap_err();
+        assert!(matches!(err, ExecError::Macro { line: 1, .. }));
+        let err = expand_text("macro f(a\nendmacro").unwrap_err();
+        assert!(matches!(err, ExecError::Macro { line: 1, .. }));
+    }
+
+    #[test]
+    fn test_locate_parse_error() {
+        let source = "macro bad(x)\npush 1\npush x\nendmacro\npush 0\n  bad(y)";
+        let expansion = expand(source, ParseOptions::default()).unwrap();
+        let err = parser::parse(&expansion.text).unwrap_err();
+        let err = expansion.locate(source, err);
+        assert_eq!((err.line, err.column), (6, 3));
+        assert_eq!(err.snippet, "  bad(y)");
+        assert_eq!(err.note.as_deref(), Some("in macro bad at line 3: push y"));
+    }
+}
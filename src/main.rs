@@ -2,19 +2,243 @@
 // Use of this source code is governed by the MIT License which can be
 // found in the LICENSE file.
 
-use spyder::run_file;
+use spyder::debugger::Debugger;
+use spyder::parser::ParseOptions;
+use spyder::repl::{Repl, DEFAULT_MAX_STEPS};
+use spyder::types::{ExecError, ParseError};
+use spyder::{
+    bytecode, compile_file, disasm, load_file, run_file, verify, Options, BYTECODE_EXTENSION,
+};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
+/// The exit status when a program's result does not fit in
+/// 0..OUT_OF_RANGE_STATUS, below the 124 timeout(1) exits with
+const OUT_OF_RANGE_STATUS: i32 = 123;
+/// The exit status when a program cannot be compiled or stops with an error
+const ERROR_STATUS: i32 = 125;
+
 #[derive(StructOpt)]
-pub struct Cli {
-    #[structopt(parse(from_os_str))]
-    pub path: std::path::PathBuf,
+pub enum Cli {
+    /// Runs a source file or a compiled .spdc file
+    Run {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(short, long)]
+        debug: bool,
+        /// Fails once this many instructions have executed
+        #[structopt(long)]
+        max_steps: Option<u64>,
+        /// Checks the stack depth of every instruction before running
+        #[structopt(long)]
+        verify: bool,
+        #[structopt(flatten)]
+        compile: CompileArgs,
+    },
+    /// Compiles a source file to .spdc bytecode
+    Compile {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        /// Defaults to the source path with a .spdc extension
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+        /// Leaves out the debug section holding source lines and names
+        #[structopt(long)]
+        strip: bool,
+        #[structopt(flatten)]
+        compile: CompileArgs,
+    },
+    /// Prints the lowered instructions of a source or .spdc file
+    Disasm {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(flatten)]
+        compile: CompileArgs,
+    },
+    /// Checks the stack depth of every instruction without running
+    Verify {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(flatten)]
+        compile: CompileArgs,
+    },
+    /// Steps through a source or .spdc file interactively
+    Debug {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(flatten)]
+        compile: CompileArgs,
+    },
+    /// Reads, lowers and executes statements one line at a time
+    Repl {
+        /// Fails a line once it has executed this many instructions
+        #[structopt(long)]
+        max_steps: Option<u64>,
+        #[structopt(flatten)]
+        parse: ParseArgs,
+    },
+}
+
+#[derive(StructOpt)]
+pub struct ParseArgs {
+    /// Accepts mnemonics in any case, e.g. Push for push
     #[structopt(short, long)]
-    pub debug: bool,
+    ignore_case: bool,
+}
+
+impl ParseArgs {
+    fn options(&self) -> ParseOptions {
+        ParseOptions {
+            ignore_case: self.ignore_case,
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct CompileArgs {
+    /// Optimizes programs compiled from source
+    #[structopt(short = "O")]
+    optimize: bool,
+    #[structopt(flatten)]
+    parse: ParseArgs,
+}
+
+impl CompileArgs {
+    fn options(&self) -> Options {
+        Options {
+            parse: self.parse.options(),
+            optimize: self.optimize,
+            ..Options::default()
+        }
+    }
 }
 
 fn main() {
-    let args = Cli::from_args();
-    let result = run_file(&args.path, args.debug);
-    println!("{:?}", result);
+    match Cli::from_args() {
+        Cli::Run {
+            path,
+            debug,
+            max_steps,
+            verify,
+            compile,
+        } => match run_file(
+            &path,
+            &Options {
+                debug,
+                max_steps,
+                verify,
+                ..compile.options()
+            },
+        ) {
+            Ok(val) => {
+                println!("{}", val);
+                std::process::exit(exit_status(val));
+            }
+            Err(err) => exit_with_error(&path, err),
+        },
+        Cli::Compile {
+            path,
+            output,
+            strip,
+            compile: args,
+        } => {
+            let output = output.unwrap_or_else(|| path.with_extension(BYTECODE_EXTENSION));
+            if let Err(err) = compile(&path, &output, strip, &args.options()) {
+                exit_with_error(&path, err);
+            }
+        }
+        Cli::Disasm { path, compile } => match load_file(&path, &compile.options()) {
+            Ok(program) => print!("{}", disasm::disassemble(&program)),
+            Err(err) => exit_with_error(&path, err),
+        },
+        Cli::Verify { path, compile } => match load_file(&path, &compile.options()) {
+            Ok(program) => {
+                let diagnostics = verify::verify_program(&program);
+                for diagnostic in diagnostics.iter() {
+                    let severity = if diagnostic.is_error() {
+                        "error"
+                    } else {
+                        "warning"
+                    };
+                    println!("{}: {}", severity, diagnostic);
+                }
+                if diagnostics.iter().any(verify::Diagnostic::is_error) {
+                    std::process::exit(ERROR_STATUS);
+                }
+            }
+            Err(err) => exit_with_error(&path, err),
+        },
+        Cli::Debug { path, compile } => match load_file(&path, &compile.options()) {
+            Ok(program) => {
+                let mut debugger = Debugger::new(program);
+                if let Err(err) = debugger.run(io::stdin().lock(), &mut io::stdout()) {
+                    exit_with_error(&path, ExecError::Io(err.to_string()));
+                }
+            }
+            Err(err) => exit_with_error(&path, err),
+        },
+        Cli::Repl { max_steps, parse } => {
+            let mut repl = Repl::new();
+            repl.options = parse.options();
+            repl.max_steps = max_steps.unwrap_or(DEFAULT_MAX_STEPS);
+            if let Err(err) = repl.run(io::stdin().lock(), &mut io::stdout()) {
+                eprintln!("error: {}", err);
+                std::process::exit(ERROR_STATUS);
+            }
+        }
+    }
+}
+
+fn compile(path: &Path, output: &Path, strip: bool, options: &Options) -> Result<(), ExecError> {
+    let mut program = compile_file(path, options)?;
+    if strip {
+        program.debug = None;
+    }
+    fs::write(output, bytecode::encode(&program)?)
+        .map_err(|err| ExecError::Io(format!("{}: {}", output.display(), err)))
+}
+
+// the process exit status for the result of a program, which is the result
+// itself when the shell can tell it apart from the statuses kept for errors
+fn exit_status(result: i64) -> i32 {
+    if (0..OUT_OF_RANGE_STATUS as i64).contains(&result) {
+        result as i32
+    } else {
+        OUT_OF_RANGE_STATUS
+    }
+}
+
+fn exit_with_error(path: &Path, err: ExecError) -> ! {
+    match err.parse_error() {
+        Some(parse_error) => print_parse_error(path, parse_error),
+        None => eprintln!("error: {}", err),
+    }
+    std::process::exit(ERROR_STATUS);
+}
+
+// prints the error along with the offending line and a caret under the
+// column the parser gave up at
+fn print_parse_error(path: &Path, err: &ParseError) {
+    let gutter = err.line.to_string();
+    eprintln!("error: {}", err);
+    let file = match &err.file {
+        Some(file) => file.clone(),
+        None => path.display().to_string(),
+    };
+    eprintln!(
+        "{}--> {}:{}:{}",
+        " ".repeat(gutter.len()),
+        file,
+        err.line,
+        err.column
+    );
+    eprintln!("{} |", " ".repeat(gutter.len()));
+    eprintln!("{} | {}", gutter, err.snippet);
+    eprintln!(
+        "{} | {}^",
+        " ".repeat(gutter.len()),
+        " ".repeat(err.column - 1)
+    );
 }
@@ -0,0 +1,114 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+/// The number of cells a program may address unless configured otherwise
+pub const DEFAULT_MEMORY_SIZE: usize = 1 << 16;
+
+/// The store that Load and Store address. Every cell from 0 up to
+/// max_size starts out as 0; the backing vector only grows as far as the
+/// program has written unless it was presized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Memory {
+    cells: Vec<i64>,
+    max_size: usize,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new(DEFAULT_MEMORY_SIZE)
+    }
+}
+
+impl Memory {
+    pub fn new(max_size: usize) -> Self {
+        Memory {
+            cells: Vec::new(),
+            max_size,
+        }
+    }
+
+    /// Allocates every cell up front so stores never reallocate
+    pub fn presized(max_size: usize) -> Self {
+        Memory {
+            cells: vec![0; max_size],
+            max_size,
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// The cells written so far, trailing cells that were never written are
+    /// omitted unless the memory was presized
+    pub fn cells(&self) -> &[i64] {
+        &self.cells
+    }
+
+    /// Returns None if the address is negative or not below max_size
+    pub fn load(&self, address: i64) -> Option<i64> {
+        let idx = self.index(address)?;
+        Some(self.cells.get(idx).copied().unwrap_or(0))
+    }
+
+    /// Returns None if the address is negative or not below max_size
+    pub fn store(&mut self, address: i64, val: i64) -> Option<()> {
+        let idx = self.index(address)?;
+        if idx >= self.cells.len() {
+            // grow geometrically so filling memory in order stays linear
+            let new_len = (idx + 1).max(self.cells.len() * 2).min(self.max_size);
+            self.cells.resize(new_len, 0);
+        }
+        self.cells[idx] = val;
+        Some(())
+    }
+
+    fn index(&self, address: i64) -> Option<usize> {
+        if address < 0 || address as u64 >= self.max_size as u64 {
+            return None;
+        }
+        Some(address as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_unwritten_cells_are_zero() {
+        let memory = Memory::new(16);
+        assert_eq!(memory.load(15), Some(0));
+        assert!(memory.cells().is_empty());
+    }
+
+    #[test]
+    fn test_store_grows_to_address() {
+        let mut memory = Memory::new(16);
+        assert_eq!(memory.store(0, 4), Some(()));
+        // storing exactly at the current length must grow the memory
+        assert_eq!(memory.store(1, 5), Some(()));
+        assert_eq!(memory.store(15, 6), Some(()));
+        assert_eq!(memory.load(1), Some(5));
+        assert_eq!(memory.load(15), Some(6));
+        assert_eq!(memory.cells().len(), 16);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let mut memory = Memory::new(16);
+        assert_eq!(memory.store(16, 1), None);
+        assert_eq!(memory.store(-1, 1), None);
+        assert_eq!(memory.store(i64::MIN, 1), None);
+        assert_eq!(memory.load(16), None);
+        assert_eq!(memory.load(-1), None);
+    }
+
+    #[test]
+    fn test_presized() {
+        let memory = Memory::presized(8);
+        assert_eq!(memory.cells(), &[0; 8]);
+        assert_eq!(memory.load(8), None);
+    }
+}
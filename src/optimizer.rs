@@ -0,0 +1,373 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+//! Rewrites short sequences of lowered instructions into fewer ones that
+//! leave the stack and memory the same.
+//!
+//! The targets of jumps and calls are moved along with the instructions they
+//! point at. A sequence is only rewritten when nothing jumps into the middle
+//! of it, and programs holding a jump that pops its destination off the
+//! stack are left alone since there is no telling where it lands.
+use std::collections::HashSet;
+
+use crate::interpreter::{branch_taken, compare};
+use crate::types::{Instruction, Instruction::*, Program, Target};
+
+/// Applies every rewrite until none of them changes the program any more.
+/// memory_size is the size of the memory the program will run with, stores
+/// to addresses outside of it are kept so they still fail.
+pub fn optimize(mut program: Program, memory_size: usize) -> Program {
+    if has_computed_jumps(&program.instructions) {
+        return program;
+    }
+    while let Some(pass) = Pass::run(&program.instructions, memory_size) {
+        program.instructions = pass.instructions;
+        let new_idx = &pass.new_idx;
+        if let Some(targets) = &mut program.jump_targets {
+            *targets = targets.iter().map(|&idx| new_idx[idx]).collect();
+        }
+        if let Some(debug) = &mut program.debug {
+            let map = &mut debug.source_map;
+            map.instruction_lines = pass
+                .origins
+                .iter()
+                .map(|&old| map.instruction_lines[old])
+                .collect();
+            map.instruction_files = pass
+                .origins
+                .iter()
+                .map(|&old| map.instruction_files[old])
+                .collect();
+            for idx in debug.labels.values_mut() {
+                *idx = new_idx[*idx];
+            }
+            debug.frames = std::mem::take(&mut debug.frames)
+                .into_iter()
+                .map(|(enter, mut frame)| {
+                    frame.end = new_idx[frame.end];
+                    (new_idx[enter], frame)
+                })
+                .collect();
+        }
+    }
+    program
+}
+
+// the result of rewriting every sequence of a program once
+struct Pass {
+    instructions: Vec<Instruction>,
+    // for every instruction the index it had before the pass, the first
+    // of the sequence it replaces
+    origins: Vec<usize>,
+    // for every index before the pass, and one past the end, the index of
+    // the instruction that now runs first from there
+    new_idx: Vec<usize>,
+}
+
+impl Pass {
+    // None when nothing could be rewritten, every rewrite leaves fewer
+    // instructions than it replaces so that shows in the length
+    fn run(instructions: &[Instruction], memory_size: usize) -> Option<Pass> {
+        let targets = jump_targets(instructions);
+        let mut pass = Pass {
+            instructions: Vec::new(),
+            origins: Vec::new(),
+            new_idx: Vec::with_capacity(instructions.len() + 1),
+        };
+        let mut idx = 0;
+        while idx < instructions.len() {
+            let rewritten = rewrite(instructions, idx, &targets, memory_size);
+            let (consumed, replacement) = match rewritten {
+                Some(rewrite) => rewrite,
+                None => (1, vec![instructions[idx].clone()]),
+            };
+            for _ in 0..consumed {
+                pass.new_idx.push(pass.instructions.len());
+            }
+            for instruction in replacement {
+                pass.origins.push(idx);
+                pass.instructions.push(instruction);
+            }
+            idx += consumed;
+        }
+        pass.new_idx.push(pass.instructions.len());
+        if pass.instructions.len() == instructions.len() {
+            return None;
+        }
+        let new_idx = &pass.new_idx;
+        for instruction in pass.instructions.iter_mut() {
+            match instruction {
+                Call(addr) if *addr < new_idx.len() => *addr = new_idx[*addr],
+                _ => {
+                    if let Some(Target::Addr(addr)) = instruction.jump_target_mut() {
+                        if *addr < new_idx.len() {
+                            *addr = new_idx[*addr];
+                        }
+                    }
+                }
+            }
+        }
+        Some(pass)
+    }
+}
+
+// how many instructions starting at idx are replaced and what by
+fn rewrite(
+    instructions: &[Instruction],
+    idx: usize,
+    targets: &HashSet<usize>,
+    memory_size: usize,
+) -> Option<(usize, Vec<Instruction>)> {
+    let window = &instructions[idx..];
+    // the rest of a sequence must only be reachable from its start
+    let straight =
+        |len: usize| len <= window.len() && (idx + 1..idx + len).all(|idx| !targets.contains(&idx));
+    match window {
+        [NoOp, ..] => Some((1, vec![])),
+        [Push(_), Pop, ..] if straight(2) => Some((2, vec![])),
+        // RePush fails on an empty stack so it only goes once a value is
+        // known to be there
+        [RePush, Pop, ..] if straight(2) && follows_value(instructions, idx, targets) => {
+            Some((2, vec![]))
+        }
+        [Push(val), Neg, ..] if straight(2) => Some((2, vec![Push(val.checked_neg()?)])),
+        // a condition known up front either always jumps or never
+        [Push(sentinal), jump, ..] if straight(2) && is_conditional(jump) => {
+            match (jump.jump_target()?, branch_taken(jump, *sentinal)) {
+                (Target::Addr(addr), true) => Some((2, vec![Goto(Target::Addr(addr))])),
+                (Target::Addr(_), false) => Some((2, vec![])),
+                (Target::Stack, _) => None,
+            }
+        }
+        [Push(arg2), Push(arg1), op, ..] if straight(3) => {
+            let folded = match op {
+                Add => arg1.checked_add(*arg2)?,
+                Sub => arg1.checked_sub(*arg2)?,
+                Mul => arg1.checked_mul(*arg2)?,
+                Div => arg1.checked_div(*arg2)?,
+                Mod => arg1.checked_rem(*arg2)?,
+                Eq | Ne | Lt | Gt | Le | Ge => compare(op, *arg1, *arg2) as i64,
+                _ => return None,
+            };
+            Some((3, vec![Push(folded)]))
+        }
+        // a value loaded right after it was stored is still on the stack
+        [Push(dest), Store, Push(src), Load, ..] if dest == src && straight(4) => {
+            Some((4, vec![RePush, Push(*dest), Store]))
+        }
+        [Push(dest), Store, ..]
+            if straight(2) && is_overwritten(&window[2..], *dest, memory_size) =>
+        {
+            Some((2, vec![Pop]))
+        }
+        _ => None,
+    }
+}
+
+// whether the address is stored to again before anything could read it,
+// in which case a store to it now is dead
+fn is_overwritten(instructions: &[Instruction], dest: i64, memory_size: usize) -> bool {
+    // an address out of range has to fail where it is first stored to
+    if dest < 0 || dest as u64 >= memory_size as u64 {
+        return false;
+    }
+    for pair in instructions.windows(2) {
+        match pair {
+            [Push(addr), Store] if *addr == dest => return true,
+            [Load, _] | [Call(_), _] | [Ret, _] | [Halt(_), _] => return false,
+            [jump, _] if jump.jump_target().is_some() => return false,
+            _ => (),
+        }
+    }
+    false
+}
+
+// whether the stack holds a value on every path reaching idx, which is the
+// case when it can only be reached from an instruction that pushes one
+fn follows_value(instructions: &[Instruction], idx: usize, targets: &HashSet<usize>) -> bool {
+    if idx == 0 || targets.contains(&idx) {
+        return false;
+    }
+    matches!(
+        instructions[idx - 1],
+        Push(_)
+            | Load
+            | LoadLocal(_)
+            | RePush
+            | Neg
+            | Add
+            | Sub
+            | Mul
+            | Div
+            | Mod
+            | Eq
+            | Ne
+            | Lt
+            | Gt
+            | Le
+            | Ge
+    )
+}
+
+fn is_conditional(instruction: &Instruction) -> bool {
+    instruction.jump_target().is_some() && !matches!(instruction, Goto(_))
+}
+
+fn has_computed_jumps(instructions: &[Instruction]) -> bool {
+    instructions
+        .iter()
+        .any(|instruction| instruction.jump_target() == Some(Target::Stack))
+}
+
+// every index a jump or call may land on
+fn jump_targets(instructions: &[Instruction]) -> HashSet<usize> {
+    let mut targets = HashSet::new();
+    for (idx, instruction) in instructions.iter().enumerate() {
+        match (instruction, instruction.jump_target()) {
+            (Call(addr), _) => {
+                targets.insert(*addr);
+                // where the call returns to
+                targets.insert(idx + 1);
+            }
+            (_, Some(Target::Addr(addr))) => {
+                targets.insert(addr);
+            }
+            _ => (),
+        }
+    }
+    targets
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::DEFAULT_MEMORY_SIZE;
+
+    fn optimize_instructions(instructions: Vec<Instruction>) -> Vec<Instruction> {
+        let program = Program {
+            instructions,
+            debug: None,
+            jump_targets: None,
+        };
+        optimize(program, DEFAULT_MEMORY_SIZE).instructions
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        assert_eq!(
+            optimize_instructions(vec![Push(2), Push(7), Sub, Push(3), Mul, Neg]),
+            vec![Push(-15)]
+        );
+        assert_eq!(
+            optimize_instructions(vec![Push(1), Push(2), Lt, NoOp, RePush, Pop]),
+            vec![Push(0)]
+        );
+        // left for the interpreter to fail on the empty stack
+        assert_eq!(
+            optimize_instructions(vec![RePush, Pop, Push(1)]),
+            vec![RePush, Pop, Push(1)]
+        );
+        // left for the interpreter to fail on
+        assert_eq!(
+            optimize_instructions(vec![Push(0), Push(1), Div]),
+            vec![Push(0), Push(1), Div]
+        );
+        assert_eq!(
+            optimize_instructions(vec![Push(1), Push(i64::MAX), Add]),
+            vec![Push(1), Push(i64::MAX), Add]
+        );
+    }
+
+    #[test]
+    fn test_stores() {
+        assert_eq!(
+            optimize_instructions(vec![Push(5), Push(3), Store, Push(3), Load]),
+            vec![Push(5), RePush, Push(3), Store]
+        );
+        assert_eq!(
+            optimize_instructions(vec![
+                Push(5),
+                Push(3),
+                Store,
+                Push(6),
+                Push(3),
+                Store,
+                Push(3),
+                Load
+            ]),
+            vec![Push(6), RePush, Push(3), Store]
+        );
+        // the second store writes back what the first one stored
+        let instructions = vec![
+            Push(5),
+            Push(3),
+            Store,
+            Push(3),
+            Load,
+            Push(3),
+            Store,
+            Push(1),
+        ];
+        assert_eq!(
+            optimize_instructions(instructions),
+            vec![Push(5), Push(3), Store, Push(1)]
+        );
+        // the first store fails before the second is reached
+        let instructions = vec![Push(5), Push(8), Store, Push(6), Push(8), Store];
+        let program = Program {
+            instructions: instructions.clone(),
+            debug: None,
+            jump_targets: None,
+        };
+        assert_eq!(optimize(program, 4).instructions, instructions);
+    }
+
+    #[test]
+    fn test_jump_targets_move() {
+        let instructions = vec![
+            NoOp,
+            Push(1),
+            Push(1),
+            Add,
+            Goto(Target::Addr(6)),
+            NoOp,
+            Push(0),
+            Push(4),
+            Call(10),
+            Add,
+            Ret,
+        ];
+        assert_eq!(
+            optimize_instructions(instructions),
+            vec![
+                Push(2),
+                Goto(Target::Addr(2)),
+                Push(0),
+                Push(4),
+                Call(6),
+                Add,
+                Ret
+            ]
+        );
+        // folded away jumps leave the code they skipped
+        assert_eq!(
+            optimize_instructions(vec![Push(1), GotoEqual(Target::Addr(3)), Push(3)]),
+            vec![Push(3)]
+        );
+        assert_eq!(
+            optimize_instructions(vec![Push(0), GotoEqual(Target::Addr(3)), Push(3), Push(5)]),
+            vec![Goto(Target::Addr(2)), Push(3), Push(5)]
+        );
+    }
+
+    #[test]
+    fn test_jumps_into_sequences() {
+        // the loop jumps to the Push between the two being folded
+        let instructions = vec![Push(1), Push(2), Add, Goto(Target::Addr(1))];
+        assert_eq!(optimize_instructions(instructions.clone()), instructions);
+        // a computed jump leaves the program alone
+        let instructions = vec![NoOp, Push(3), Push(1), Add, Goto(Target::Stack)];
+        assert_eq!(optimize_instructions(instructions.clone()), instructions);
+    }
+}
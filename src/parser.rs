@@ -2,21 +2,34 @@
 // Use of this source code is governed by the MIT License which can be
 // found in the LICENSE file.
 
-use crate::types::{Instruction, Instruction::*};
+use crate::types::{Instruction, Instruction::*, ParseError};
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    character::complete::{alphanumeric1, digit1, line_ending, not_line_ending},
-    combinator::opt,
-    error::{context, VerboseError},
-    multi::many1,
-    sequence::{separated_pair, tuple},
-    IResult,
+    bytes::complete::{is_not, tag, tag_no_case},
+    character::complete::{
+        alphanumeric1, char, digit1, line_ending, not_line_ending, one_of, space0, space1,
+    },
+    combinator::{all_consuming, consumed, cut, eof, map, map_res, not, opt, peek, recognize},
+    error::{context, convert_error, VerboseError, VerboseErrorKind},
+    multi::{fold_many0, many0, separated_list0},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    Err, IResult, Offset,
 };
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Code<'a> {
     pub lines: Vec<Statement<'a>>,
+    // where each statement in lines came from in the source
+    pub spans: Vec<Span>,
+}
+
+/// The byte range of a statement in the source and the 1 based line it
+/// starts on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -27,10 +40,34 @@ pub enum Statement<'a> {
     LabeledStatement(LabeledStatement<'a>),
     Goto(Label<'a>),
     GotoEqual(Label<'a>),
+    GotoNotEqual(Label<'a>),
+    GotoLess(Label<'a>),
+    GotoGreater(Label<'a>),
+    GotoLessEqual(Label<'a>),
+    GotoGreaterEqual(Label<'a>),
     Call(Label<'a>),
+    // starts a function body that runs until the matching End
+    Func(FuncDef<'a>),
+    // lowers the statements of another file here, the path is relative to
+    // the importing file
+    Import(&'a str),
+    // run the following statements if the condition holds, up to an Else
+    // or the matching End
+    If(Condition<'a>),
+    Else,
+    // repeat the following statements up to the matching End while the
+    // condition holds
+    While(Condition<'a>),
+    End,
     Comment(&'a str),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncDef<'a> {
+    pub name: Label<'a>,
+    pub params: Vec<&'a str>,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum VariableExpr<'a> {
     Assignment(Assignment<'a>),
@@ -40,7 +77,89 @@ pub enum VariableExpr<'a> {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Assignment<'a> {
     pub name: &'a str,
-    pub value: i64,
+    pub value: Expr<'a>,
+}
+
+/// What an if or while tests, a lone expression holds when it is not zero
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Condition<'a> {
+    NonZero(Expr<'a>),
+    Compare(Expr<'a>, Comparison, Expr<'a>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl Comparison {
+    fn from_str(op: &str) -> Self {
+        match op {
+            "==" => Self::Eq,
+            "!=" => Self::Ne,
+            "<" => Self::Lt,
+            ">" => Self::Gt,
+            "<=" => Self::Le,
+            ">=" => Self::Ge,
+            _ => panic!("condition parser got an unknown comparison {}", op),
+        }
+    }
+
+    pub fn get_value(&self) -> Instruction {
+        match self {
+            Self::Eq => Instruction::Eq,
+            Self::Ne => Instruction::Ne,
+            Self::Lt => Instruction::Lt,
+            Self::Gt => Instruction::Gt,
+            Self::Le => Instruction::Le,
+            Self::Ge => Instruction::Ge,
+        }
+    }
+}
+
+/// The right hand side of an assignment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr<'a> {
+    Number(i64),
+    Variable(&'a str),
+    Binary(Box<Expr<'a>>, Operator, Box<Expr<'a>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+}
+
+impl Operator {
+    fn from_char(op: char) -> Self {
+        match op {
+            '+' => Self::Add,
+            '-' => Self::Sub,
+            '*' => Self::Mul,
+            '/' => Self::Div,
+            '%' => Self::Mod,
+            _ => panic!("expression parser got an unknown operator {}", op),
+        }
+    }
+
+    pub fn get_value(&self) -> Instruction {
+        match self {
+            Self::Add => Instruction::Add,
+            Self::Sub => Instruction::Sub,
+            Self::Mul => Instruction::Mul,
+            Self::Div => Instruction::Div,
+            Self::Mod => Instruction::Mod,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -74,27 +193,239 @@ pub struct LabeledStatement<'a> {
 
 type Res<T, U> = IResult<T, U, VerboseError<T>>;
 
-pub fn code(input: &str) -> Res<&str, Code> {
+/// Every mnemonic and keyword in the case it must be written in unless
+/// ParseOptions::ignore_case is set
+const KEYWORDS: &[&str] = &[
+    "push",
+    "load",
+    "store",
+    "pop",
+    "goto",
+    "gotoEqual",
+    "gotoNotEqual",
+    "gotoLess",
+    "gotoGreater",
+    "gotoLessEqual",
+    "gotoGreaterEqual",
+    "gotoE",
+    "gotoNE",
+    "gotoL",
+    "gotoG",
+    "gotoLE",
+    "gotoGE",
+    "rePush",
+    "noOp",
+    "call",
+    "return",
+    "halt",
+    "func",
+    "import",
+    "end",
+    "if",
+    "else",
+    "while",
+    "var",
+    "add",
+    "sub",
+    "mul",
+    "div",
+    "mod",
+    "neg",
+    "eq",
+    "ne",
+    "lt",
+    "gt",
+    "le",
+    "ge",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ParseOptions {
+    // accept mnemonics in any case, e.g. Push or PUSH for push
+    pub ignore_case: bool,
+}
+
+/// Parses a whole source file, converting nom's error into a ParseError
+/// that points at the offending line and column.
+pub fn parse(input: &str) -> Result<Code<'_>, ParseError> {
+    parse_with(input, ParseOptions::default())
+}
+
+pub fn parse_with(input: &str, options: ParseOptions) -> Result<Code<'_>, ParseError> {
+    match code(input) {
+        Ok((_, parsed)) => {
+            if !options.ignore_case {
+                for span in parsed.spans.iter() {
+                    check_case(input, span.start)?;
+                }
+            }
+            Ok(parsed)
+        }
+        Err(Err::Error(err)) | Err(Err::Failure(err)) => Err(to_parse_error(input, err)),
+        Err(Err::Incomplete(_)) => panic!("complete parsers returned incomplete"),
+    }
+}
+
+/// Parses a single statement such as a line typed into the REPL, anything
+/// left over after it is an error.
+pub fn parse_statement(input: &str) -> Result<Statement<'_>, ParseError> {
+    parse_statement_with(input, ParseOptions::default())
+}
+
+pub fn parse_statement_with(
+    input: &str,
+    options: ParseOptions,
+) -> Result<Statement<'_>, ParseError> {
+    let line = terminated(preceded(space0, consumed(statement)), trailing);
+    match all_consuming(line)(input) {
+        Ok((_, (text, parsed))) => {
+            if !options.ignore_case {
+                check_case(input, input.offset(text))?;
+            }
+            Ok(parsed)
+        }
+        Err(Err::Error(err)) | Err(Err::Failure(err)) => Err(to_parse_error(input, err)),
+        Err(Err::Incomplete(_)) => panic!("complete parsers returned incomplete"),
+    }
+}
+
+// the parsers match mnemonics in any case, so in strict mode the statement
+// starting at start is checked to spell its mnemonic as listed in KEYWORDS
+fn check_case(input: &str, start: usize) -> Result<(), ParseError> {
+    let mut offset = start;
+    if input[offset..].starts_with("!![") {
+        // the label was parsed so the closing bracket is there
+        offset += input[offset..].find(']').unwrap_or(0) + 1;
+        offset += input[offset..].len() - input[offset..].trim_start().len();
+    }
+    let word_len = input[offset..]
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(input.len() - offset);
+    let word = &input[offset..offset + word_len];
+    let keyword = KEYWORDS
+        .iter()
+        .find(|keyword| keyword.eq_ignore_ascii_case(word));
+    match keyword {
+        Some(keyword) if *keyword != word => {
+            let line_start = input[..offset].rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+            Err(ParseError {
+                file: None,
+                line: input[..offset].matches('\n').count() + 1,
+                column: input[line_start..offset].chars().count() + 1,
+                expected: Box::new([format!("'{}', mnemonics are case sensitive", keyword)]),
+                snippet: input[line_start..]
+                    .lines()
+                    .next()
+                    .unwrap_or("")
+                    .trim_end()
+                    .to_string(),
+                trace: String::new(),
+                note: None,
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+fn to_parse_error(input: &str, err: VerboseError<&str>) -> ParseError {
+    // the first entry is where the innermost parser gave up
+    let offset = err
+        .errors
+        .first()
+        .map(|(remaining, _)| input.offset(remaining))
+        .unwrap_or(0);
+    let line_start = input[..offset].rfind('\n').map(|idx| idx + 1).unwrap_or(0);
+    let snippet = input[line_start..]
+        .lines()
+        .next()
+        .unwrap_or("")
+        .trim_end()
+        .to_string();
+    let mut expected: Vec<String> = Vec::new();
+    for (_, kind) in err.errors.iter() {
+        let token = match kind {
+            VerboseErrorKind::Context(ctx) => ctx.to_string(),
+            VerboseErrorKind::Char(c) => format!("'{}'", c),
+            VerboseErrorKind::Nom(_) => continue,
+        };
+        if !expected.contains(&token) {
+            expected.push(token);
+        }
+    }
+    ParseError {
+        file: None,
+        line: input[..offset].matches('\n').count() + 1,
+        column: input[line_start..offset].chars().count() + 1,
+        expected: expected.into(),
+        snippet,
+        trace: convert_error(input, err),
+        note: None,
+    }
+}
+
+/// Parses every line of a source file, which may be empty and may end with
+/// a line ending. Anything that is not a statement is an error.
+pub fn code(input: &str) -> Res<&str, Code<'_>> {
     context(
         "code",
-        tuple((many1(tuple((statement, line_ending))), statement)),
+        tuple((
+            many0(terminated(line, line_ending)),
+            terminated(line, context("end of line", eof)),
+        )),
     )(input)
     .map(|(next_input, res)| {
         let mut lines: Vec<Statement> = Vec::new();
-        res.0.iter().for_each(|(stmt, _)| lines.push(stmt.clone()));
-        lines.push(res.1);
-        (next_input, Code { lines })
+        let mut spans: Vec<Span> = Vec::new();
+        let mut line = 1;
+        let mut counted_to = 0;
+        let statements = res.0.into_iter().chain(std::iter::once(res.1)).flatten();
+        for (text, stmt) in statements {
+            let start = input.offset(text);
+            line += input[counted_to..start].matches('\n').count();
+            counted_to = start;
+            spans.push(Span {
+                start,
+                end: start + text.len(),
+                line,
+            });
+            lines.push(stmt);
+        }
+        (next_input, Code { lines, spans })
     })
 }
 
-fn statement(input: &str) -> Res<&str, Statement> {
+// a line holding nothing but whitespace, or an indented statement followed
+// by an optional comment
+fn line(input: &str) -> Res<&str, Option<(&str, Statement<'_>)>> {
+    preceded(
+        space0,
+        alt((
+            map(peek(alt((line_ending, eof))), |_| None),
+            map(terminated(consumed(statement), trailing), Some),
+        )),
+    )(input)
+}
+
+// whitespace and a comment allowed after a statement
+fn trailing(input: &str) -> Res<&str, ()> {
+    map(pair(space0, opt(comment)), |_| ())(input)
+}
+
+pub fn statement(input: &str) -> Res<&str, Statement<'_>> {
     context(
         "instruction",
         alt((
             labled_ins,
             goto,
             push,
+            halt,
             call,
+            func,
+            import,
+            if_block,
+            else_block,
+            while_block,
+            block_end,
             comment,
             variable_expression,
             plain_statement,
@@ -102,78 +433,222 @@ fn statement(input: &str) -> Res<&str, Statement> {
     )(input)
 }
 
-fn labled_ins(input: &str) -> Res<&str, Statement> {
+fn labled_ins(input: &str) -> Res<&str, Statement<'_>> {
     context(
         "labled ins",
-        tuple((tag("!!["), alphanumeric1, tag("] "), statement)),
+        tuple((
+            tag("!!["),
+            cut(context("label", alphanumeric1)),
+            cut(char(']')),
+            space0,
+            statement,
+        )),
     )(input)
     .map(|(next_input, res)| {
         (
             next_input,
             Statement::LabeledStatement(LabeledStatement {
                 label: res.1,
-                statement: Box::new(res.3),
+                statement: Box::new(res.4),
             }),
         )
     })
 }
 
-fn goto(input: &str) -> Res<&str, Statement> {
+fn goto(input: &str) -> Res<&str, Statement<'_>> {
     context(
-        "goto or goto if equal",
+        "goto or conditional goto",
         alt((
-            separated_pair(tag("goto"), tag(" "), alphanumeric1),
-            separated_pair(tag("gotoEqual"), tag(" "), alphanumeric1),
+            separated_pair(tag_no_case("goto"), space1, label),
+            separated_pair(tag_no_case("gotoEqual"), space1, label),
+            separated_pair(tag_no_case("gotoNotEqual"), space1, label),
+            separated_pair(tag_no_case("gotoLess"), space1, label),
+            separated_pair(tag_no_case("gotoGreater"), space1, label),
+            separated_pair(tag_no_case("gotoLessEqual"), space1, label),
+            separated_pair(tag_no_case("gotoGreaterEqual"), space1, label),
+            // short forms of the conditional gotos
+            separated_pair(tag_no_case("gotoE"), space1, label),
+            separated_pair(tag_no_case("gotoNE"), space1, label),
+            separated_pair(tag_no_case("gotoL"), space1, label),
+            separated_pair(tag_no_case("gotoG"), space1, label),
+            separated_pair(tag_no_case("gotoLE"), space1, label),
+            separated_pair(tag_no_case("gotoGE"), space1, label),
         )),
     )(input)
     .map(|(next_input, res)| {
         (
             next_input,
-            match res.0 {
+            match res.0.to_ascii_lowercase().as_str() {
                 "goto" => Statement::Goto(res.1),
-                "gotoEqual" => Statement::GotoEqual(res.1),
-                _ => panic!("goto parser tried to parse non goto or gotoifequal"),
+                "gotoequal" | "gotoe" => Statement::GotoEqual(res.1),
+                "gotonotequal" | "gotone" => Statement::GotoNotEqual(res.1),
+                "gotoless" | "gotol" => Statement::GotoLess(res.1),
+                "gotogreater" | "gotog" => Statement::GotoGreater(res.1),
+                "gotolessequal" | "gotole" => Statement::GotoLessEqual(res.1),
+                "gotogreaterequal" | "gotoge" => Statement::GotoGreaterEqual(res.1),
+                _ => panic!("goto parser tried to parse non goto or conditional goto"),
             },
         )
     })
 }
 
-fn call(input: &str) -> Res<&str, Statement> {
-    context("call", separated_pair(tag("call"), tag(" "), alphanumeric1))(input)
+fn call(input: &str) -> Res<&str, Statement<'_>> {
+    context("call", separated_pair(tag_no_case("call"), space1, label))(input)
         .map(|(next_input, res)| (next_input, Statement::Call(res.1)))
 }
 
-fn push(input: &str) -> Res<&str, Statement> {
-    context("push", separated_pair(tag("push"), tag(" "), number))(input).map(
-        |(next_input, res)| {
-            let num = res.1;
-            (next_input, Statement::Ins(Push(num)))
-        },
-    )
+fn func(input: &str) -> Res<&str, Statement<'_>> {
+    context(
+        "func",
+        preceded(
+            pair(tag_no_case("func"), space1),
+            cut(tuple((
+                context("function name", alphanumeric1),
+                preceded(space0, char('(')),
+                delimited(
+                    space0,
+                    separated_list0(
+                        tuple((space0, char(','), space0)),
+                        context("parameter", alphanumeric1),
+                    ),
+                    space0,
+                ),
+                char(')'),
+            ))),
+        ),
+    )(input)
+    .map(|(next_input, res)| {
+        (
+            next_input,
+            Statement::Func(FuncDef {
+                name: res.0,
+                params: res.2,
+            }),
+        )
+    })
 }
 
-// turns positive or negative decimal numbers to i64
+fn import(input: &str) -> Res<&str, Statement<'_>> {
+    context(
+        "import",
+        preceded(
+            pair(tag_no_case("import"), space1),
+            cut(context(
+                "quoted path",
+                delimited(char('"'), is_not("\"\r\n"), char('"')),
+            )),
+        ),
+    )(input)
+    .map(|(next_input, path)| (next_input, Statement::Import(path)))
+}
+
+fn if_block(input: &str) -> Res<&str, Statement<'_>> {
+    context(
+        "if",
+        preceded(pair(tag_no_case("if"), space1), cut(condition)),
+    )(input)
+    .map(|(next_input, res)| (next_input, Statement::If(res)))
+}
+
+fn else_block(input: &str) -> Res<&str, Statement<'_>> {
+    context("else", tag_no_case("else"))(input).map(|(next_input, _)| (next_input, Statement::Else))
+}
+
+fn while_block(input: &str) -> Res<&str, Statement<'_>> {
+    context(
+        "while",
+        preceded(pair(tag_no_case("while"), space1), cut(condition)),
+    )(input)
+    .map(|(next_input, res)| (next_input, Statement::While(res)))
+}
+
+fn condition(input: &str) -> Res<&str, Condition<'_>> {
+    let comparison = alt((
+        tag("=="),
+        tag("!="),
+        tag("<="),
+        tag(">="),
+        tag("<"),
+        tag(">"),
+    ));
+    context(
+        "condition",
+        pair(
+            expression,
+            opt(pair(delimited(space0, comparison, space0), cut(expression))),
+        ),
+    )(input)
+    .map(|(next_input, (left, compare))| {
+        let cond = match compare {
+            Some((op, right)) => Condition::Compare(left, Comparison::from_str(op), right),
+            None => Condition::NonZero(left),
+        };
+        (next_input, cond)
+    })
+}
+
+fn block_end(input: &str) -> Res<&str, Statement<'_>> {
+    context("end", tag_no_case("end"))(input).map(|(next_input, _)| (next_input, Statement::End))
+}
+
+fn push(input: &str) -> Res<&str, Statement<'_>> {
+    context(
+        "push",
+        separated_pair(tag_no_case("push"), space1, cut(number)),
+    )(input)
+    .map(|(next_input, res)| {
+        let num = res.1;
+        (next_input, Statement::Ins(Push(num)))
+    })
+}
+
+// halt with an optional exit code
+fn halt(input: &str) -> Res<&str, Statement<'_>> {
+    context(
+        "halt",
+        preceded(tag_no_case("halt"), opt(preceded(space1, number))),
+    )(input)
+    .map(|(next_input, code)| (next_input, Statement::Ins(Halt(code))))
+}
+
+// turns positive or negative decimal numbers to i64, failing on literals
+// that do not fit
 fn number(input: &str) -> Res<&str, i64> {
-    context("is plus-minus digit", tuple((opt(tag("-")), digit1)))(input).map(
-        |(next_input, res)| {
-            let multiple = if res.0.is_some() { -1 } else { 1 };
-            let num: i64 = res.1.parse::<i64>().expect("not a number");
-            (next_input, num * multiple)
-        },
-    )
+    context(
+        "number",
+        map_res(recognize(pair(opt(char('-')), digit1)), |num: &str| {
+            num.parse::<i64>()
+        }),
+    )(input)
+}
+
+// the name after a goto or call, once the mnemonic has matched it is an
+// error for it to be missing
+fn label(input: &str) -> Res<&str, &str> {
+    cut(context("label", qualified_name))(input)
 }
 
-fn variable_expression(input: &str) -> Res<&str, Statement> {
+// a label or variable name that may be prefixed with the modules it comes
+// from, e.g. math::multiply
+fn qualified_name(input: &str) -> Res<&str, &str> {
+    recognize(pair(alphanumeric1, many0(pair(tag("::"), alphanumeric1))))(input)
+}
+
+fn variable_expression(input: &str) -> Res<&str, Statement<'_>> {
     context("expression with variables", alt((assignment, load_store)))(input)
         .map(|(next_input, res)| (next_input, Statement::VarExpr(res)))
 }
 
-fn assignment(input: &str) -> Res<&str, VariableExpr> {
+fn assignment(input: &str) -> Res<&str, VariableExpr<'_>> {
     context(
         "assignment",
         tuple((
-            tag("var "),
-            separated_pair(alphanumeric1, tag(" = "), number),
+            pair(tag_no_case("var"), space1),
+            cut(separated_pair(
+                context("variable name", alphanumeric1),
+                context("'='", delimited(space0, char('='), space0)),
+                expression,
+            )),
         )),
     )(input)
     .map(|(next_input, res)| {
@@ -187,20 +662,62 @@ fn assignment(input: &str) -> Res<&str, VariableExpr> {
     })
 }
 
-fn load_store(input: &str) -> Res<&str, VariableExpr> {
+// sums and differences of terms, operators of the same precedence
+// associate to the left
+fn expression(input: &str) -> Res<&str, Expr<'_>> {
+    let (input, first) = context("expression", term)(input)?;
+    fold_many0(
+        pair(delimited(space0, one_of("+-"), space0), cut(term)),
+        first,
+        |left, (op, right)| Expr::Binary(Box::new(left), Operator::from_char(op), Box::new(right)),
+    )(input)
+}
+
+fn term(input: &str) -> Res<&str, Expr<'_>> {
+    let (input, first) = factor(input)?;
+    fold_many0(
+        pair(
+            // a second slash starts a comment rather than a divisor
+            delimited(
+                space0,
+                alt((one_of("*%"), terminated(char('/'), not(char('/'))))),
+                space0,
+            ),
+            cut(factor),
+        ),
+        first,
+        |left, (op, right)| Expr::Binary(Box::new(left), Operator::from_char(op), Box::new(right)),
+    )(input)
+}
+
+fn factor(input: &str) -> Res<&str, Expr<'_>> {
+    context(
+        "number, variable or '('",
+        alt((
+            map(number, Expr::Number),
+            map(qualified_name, Expr::Variable),
+            delimited(
+                pair(char('('), space0),
+                expression,
+                cut(pair(space0, char(')'))),
+            ),
+        )),
+    )(input)
+}
+
+fn load_store(input: &str) -> Res<&str, VariableExpr<'_>> {
     context(
         "load store",
-        separated_pair(
-            alt((tag("load"), tag("store"))),
-            opt(tag(" ")),
-            opt(alphanumeric1),
+        pair(
+            alt((tag_no_case("load"), tag_no_case("store"))),
+            opt(preceded(space1, qualified_name)),
         ),
     )(input)
     .map(|(next_input, res)| {
         (
             next_input,
             VariableExpr::Access(VarAccess {
-                load_or_store: match res.0 {
+                load_or_store: match res.0.to_ascii_lowercase().as_str() {
                     "load" => LoadOrStore::Load,
                     "store" => LoadOrStore::Store,
                     _ => panic!("load_store parser got something other than load store"),
@@ -213,43 +730,59 @@ fn load_store(input: &str) -> Res<&str, VariableExpr> {
 
 /// Parses parameter less instructions into Instruction
 /// i.e. not Push or Goto
-fn plain_statement(input: &str) -> Res<&str, Statement> {
+fn plain_statement(input: &str) -> Res<&str, Statement<'_>> {
     context(
         "plain instruction",
         alt((
-            tag("load"),
-            tag("store"),
-            tag("pop"),
-            tag("rePush"),
-            tag("noOp"),
-            tag("return"),
-            tag("add"),
-            tag("sub"),
-            tag("mul"),
-            tag("div"),
+            tag_no_case("load"),
+            tag_no_case("store"),
+            tag_no_case("pop"),
+            tag_no_case("rePush"),
+            tag_no_case("noOp"),
+            tag_no_case("return"),
+            tag_no_case("add"),
+            tag_no_case("sub"),
+            tag_no_case("mul"),
+            tag_no_case("div"),
+            tag_no_case("mod"),
+            tag_no_case("neg"),
+            tag_no_case("eq"),
+            tag_no_case("ne"),
+            tag_no_case("lt"),
+            tag_no_case("gt"),
+            tag_no_case("le"),
+            tag_no_case("ge"),
         )),
     )(input)
     .map(|(next_input, res)| {
         (
             next_input,
-            Statement::Ins(match res {
+            Statement::Ins(match res.to_ascii_lowercase().as_str() {
                 "load" => Load,
                 "store" => Store,
                 "pop" => Pop,
-                "rePush" => RePush,
-                "return" => Goto,
+                "repush" => RePush,
+                "return" => Ret,
                 "add" => Add,
                 "sub" => Sub,
                 "mul" => Mul,
                 "div" => Div,
-                "noOp" => NoOp,
+                "mod" => Mod,
+                "neg" => Neg,
+                "noop" => NoOp,
+                "eq" => Eq,
+                "ne" => Ne,
+                "lt" => Lt,
+                "gt" => Gt,
+                "le" => Le,
+                "ge" => Ge,
                 _ => panic!("plain instruction tried to parse an non plain instruciton"),
             }),
         )
     })
 }
 
-fn comment(input: &str) -> Res<&str, Statement> {
+fn comment(input: &str) -> Res<&str, Statement<'_>> {
     context("comment", tuple((tag("//"), not_line_ending)))(input)
         .map(|(next_input, res)| (next_input, Statement::Comment(res.1)))
 }
@@ -272,12 +805,118 @@ mod test {
                             label: "END",
                             statement: Box::new(Statement::Ins(RePush)),
                         })
+                    ],
+                    spans: vec![
+                        Span {
+                            start: 0,
+                            end: 15,
+                            line: 1
+                        },
+                        Span {
+                            start: 17,
+                            end: 25,
+                            line: 2
+                        },
+                        Span {
+                            start: 27,
+                            end: 41,
+                            line: 3
+                        },
                     ]
                 }
             ))
         )
     }
 
+    #[test]
+    fn test_whitespace() {
+        let source =
+            "  push  2   \n\n\tpush\t3 // three\n!![END]   add\t\nvar  x=4\nfunc f( a ,b )";
+        let parsed = parse(source).unwrap();
+        assert_eq!(
+            parsed.lines,
+            vec![
+                Statement::Ins(Push(2)),
+                Statement::Ins(Push(3)),
+                Statement::LabeledStatement(LabeledStatement {
+                    label: "END",
+                    statement: Box::new(Statement::Ins(Add)),
+                }),
+                Statement::VarExpr(VariableExpr::Assignment(Assignment {
+                    name: "x",
+                    value: Expr::Number(4),
+                })),
+                Statement::Func(FuncDef {
+                    name: "f",
+                    params: vec!["a", "b"],
+                }),
+            ]
+        );
+        let lines: Vec<usize> = parsed.spans.iter().map(|span| span.line).collect();
+        assert_eq!(lines, vec![1, 3, 4, 5, 6]);
+        assert_eq!(
+            &source[parsed.spans[1].start..parsed.spans[1].end],
+            "push\t3"
+        );
+    }
+
+    #[test]
+    fn test_case() {
+        let source = "Push 1\nPUSH 2\n!![end] gotoequal end\nReturn";
+        let err = parse(source).unwrap_err();
+        assert_eq!((err.line, err.column), (1, 1));
+        assert_eq!(err.expected[0], "'push', mnemonics are case sensitive");
+        let options = ParseOptions { ignore_case: true };
+        let parsed = parse_with(source, options).unwrap();
+        assert_eq!(parsed.lines[1], Statement::Ins(Push(2)));
+        assert_eq!(parsed.lines[3], Statement::Ins(Ret));
+        assert!(parse_statement("  !![A] rePush // again").is_ok());
+        let err = parse_statement("  !![A] RePush").unwrap_err();
+        assert_eq!(err.column, 9);
+        assert!(parse_statement_with("  !![A] RePush // again", options).is_ok());
+    }
+
+    #[test]
+    fn test_code_line_counts() {
+        assert_eq!(parse("").unwrap().lines, vec![]);
+        assert_eq!(parse("\n\n").unwrap().lines, vec![]);
+        assert_eq!(parse("add").unwrap().lines, vec![Statement::Ins(Add)]);
+        let parsed = parse("push 1\r\nadd\r\n").unwrap();
+        assert_eq!(parsed.lines.len(), 2);
+        assert_eq!(parsed.spans[1].line, 2);
+        let parsed = parse("push 1\nadd\r\npop\n").unwrap();
+        assert_eq!(parsed.lines.len(), 3);
+    }
+
+    #[test]
+    fn test_leftover_input() {
+        let err = parse("push 1\nadd 2\npop").unwrap_err();
+        assert_eq!((err.line, err.column), (2, 5));
+        assert_eq!(
+            err.expected.first().map(String::as_str),
+            Some("end of line")
+        );
+        let err = parse("push 1 2").unwrap_err();
+        assert_eq!((err.line, err.column), (1, 8));
+    }
+
+    #[test]
+    fn test_parse_error_location() {
+        let err = parse("push 1\r\npush 2\r\npush x\r\nadd").unwrap_err();
+        assert_eq!(err.line, 3);
+        assert_eq!(err.column, 6);
+        assert_eq!(err.snippet, "push x");
+        assert_eq!(err.expected.first().map(String::as_str), Some("number"));
+    }
+
+    #[test]
+    fn test_parse_error_unknown_instruction() {
+        let err = parse("push 1\npussh 2\nadd").unwrap_err();
+        assert_eq!(err.line, 2);
+        assert_eq!(err.column, 1);
+        assert_eq!(err.snippet, "pussh 2");
+    }
+
     #[test]
     fn test_statement() {
         let res = statement("!![END] mul");
@@ -314,12 +953,54 @@ mod test {
         assert_eq!(res, Ok(("", Statement::Goto("END"))));
         res = goto("gotoEqual TopOfLoop");
         assert_eq!(res, Ok(("", Statement::GotoEqual("TopOfLoop"))));
+        res = goto("gotoNotEqual END");
+        assert_eq!(res, Ok(("", Statement::GotoNotEqual("END"))));
+        res = goto("gotoLess END");
+        assert_eq!(res, Ok(("", Statement::GotoLess("END"))));
+        res = goto("gotoLessEqual END");
+        assert_eq!(res, Ok(("", Statement::GotoLessEqual("END"))));
+        res = goto("gotoGreater END");
+        assert_eq!(res, Ok(("", Statement::GotoGreater("END"))));
+        res = goto("gotoGreaterEqual END");
+        assert_eq!(res, Ok(("", Statement::GotoGreaterEqual("END"))));
+        res = goto("gotoG END");
+        assert_eq!(res, Ok(("", Statement::GotoGreater("END"))));
+        res = goto("gotoLE END");
+        assert_eq!(res, Ok(("", Statement::GotoLessEqual("END"))));
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let mut res = plain_statement("lt");
+        assert_eq!(res, Ok(("", Statement::Ins(Lt))));
+        res = plain_statement("ge");
+        assert_eq!(res, Ok(("", Statement::Ins(Ge))));
+        res = plain_statement("ne");
+        assert_eq!(res, Ok(("", Statement::Ins(Ne))));
+        res = plain_statement("neg");
+        assert_eq!(res, Ok(("", Statement::Ins(Neg))));
     }
 
     #[test]
     fn test_push() {
         let res = push("push -50");
-        assert_eq!(res, Ok(("", Statement::Ins(Push(-50)))))
+        assert_eq!(res, Ok(("", Statement::Ins(Push(-50)))));
+        let res = push("push -9223372036854775808");
+        assert_eq!(res, Ok(("", Statement::Ins(Push(i64::MIN)))));
+        assert!(matches!(
+            push("push 9223372036854775808"),
+            Err(Err::Failure(_))
+        ));
+    }
+
+    #[test]
+    fn test_halt() {
+        let res = halt("halt");
+        assert_eq!(res, Ok(("", Statement::Ins(Halt(None)))));
+        let res = halt("halt -3");
+        assert_eq!(res, Ok(("", Statement::Ins(Halt(Some(-3))))));
+        let parsed = parse("halt // done").unwrap();
+        assert_eq!(parsed.lines, vec![Statement::Ins(Halt(None))]);
     }
 
     #[test]
@@ -327,6 +1008,75 @@ mod test {
         let res = call("call PerformCalc2");
         assert_eq!(res, Ok(("", Statement::Call("PerformCalc2"))));
     }
+    #[test]
+    fn test_func() {
+        let res = func("func multiply(a, b)");
+        assert_eq!(
+            res,
+            Ok((
+                "",
+                Statement::Func(FuncDef {
+                    name: "multiply",
+                    params: vec!["a", "b"],
+                })
+            ))
+        );
+        let res = func("func main()");
+        assert_eq!(
+            res,
+            Ok((
+                "",
+                Statement::Func(FuncDef {
+                    name: "main",
+                    params: vec![],
+                })
+            ))
+        );
+        assert!(matches!(func("func main"), Err(Err::Failure(_))));
+    }
+
+    #[test]
+    fn test_blocks() {
+        let res = statement("if a * 2 >= b");
+        assert_eq!(
+            res,
+            Ok((
+                "",
+                Statement::If(Condition::Compare(
+                    Expr::Binary(
+                        Box::new(Expr::Variable("a")),
+                        Operator::Mul,
+                        Box::new(Expr::Number(2))
+                    ),
+                    Comparison::Ge,
+                    Expr::Variable("b"),
+                ))
+            ))
+        );
+        let res = statement("while  count");
+        assert_eq!(
+            res,
+            Ok((
+                "",
+                Statement::While(Condition::NonZero(Expr::Variable("count")))
+            ))
+        );
+        assert_eq!(statement("else"), Ok(("", Statement::Else)));
+        assert!(matches!(statement("if"), Err(Err::Error(_))));
+        assert!(matches!(statement("while x <"), Err(Err::Failure(_))));
+    }
+
+    #[test]
+    fn test_import() {
+        let res = statement("import \"lib/math.spd\"");
+        assert_eq!(res, Ok(("", Statement::Import("lib/math.spd"))));
+        assert!(matches!(statement("import math"), Err(Err::Failure(_))));
+        let res = statement("call math::multiply");
+        assert_eq!(res, Ok(("", Statement::Call("math::multiply"))));
+        let res = statement("var x = util::count + 1");
+        assert!(matches!(res, Ok(("", _))));
+    }
+
     #[test]
     fn test_var_assignment() {
         let res = assignment("var foo = 5");
@@ -336,12 +1086,53 @@ mod test {
                 "",
                 VariableExpr::Assignment(Assignment {
                     name: "foo",
-                    value: 5,
+                    value: Expr::Number(5),
                 })
             ))
         );
     }
 
+    #[test]
+    fn test_expression() {
+        use Operator::*;
+        let num = |val| Box::new(Expr::Number(val));
+        let var = |name| Box::new(Expr::Variable(name));
+        let res = expression("a * b + 3");
+        assert_eq!(
+            res,
+            Ok((
+                "",
+                Expr::Binary(Box::new(Expr::Binary(var("a"), Mul, var("b"))), Add, num(3))
+            ))
+        );
+        let res = expression("10 - (x-2) % -4 // rest");
+        assert_eq!(
+            res,
+            Ok((
+                " // rest",
+                Expr::Binary(
+                    num(10),
+                    Sub,
+                    Box::new(Expr::Binary(
+                        Box::new(Expr::Binary(var("x"), Sub, num(2))),
+                        Mod,
+                        num(-4)
+                    ))
+                )
+            ))
+        );
+        let res = expression("8 / 4 / 2");
+        assert_eq!(
+            res,
+            Ok((
+                "",
+                Expr::Binary(Box::new(Expr::Binary(num(8), Div, num(4))), Div, num(2))
+            ))
+        );
+        assert!(matches!(expression("(1 + 2"), Err(Err::Failure(_))));
+        assert!(matches!(expression("1 +"), Err(Err::Failure(_))));
+    }
+
     #[test]
     fn test_load() {
         let mut res = load_store("load foo");
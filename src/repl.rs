@@ -0,0 +1,289 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+//! A read-eval-print loop that lowers and executes one statement at a time
+//! against a persistent compiler and interpreter.
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::codegen::CodeGen;
+use crate::interpreter::{Interpreter, StepResult};
+use crate::linker::Linker;
+use crate::parser::{self, ParseOptions};
+use crate::types::{ExecError, SourceMap};
+
+const SOURCE_NAME: &str = "<repl>";
+
+/// How many instructions a line may execute unless configured otherwise
+pub const DEFAULT_MAX_STEPS: u64 = 10_000_000;
+
+pub struct Repl {
+    pub options: ParseOptions,
+    /// Fails a line once it has executed this many instructions so a loop
+    /// that never ends gives the prompt back
+    pub max_steps: u64,
+    compiler: CodeGen,
+    // the imports of every file loaded so far
+    linker: Linker,
+    // its source map holds the lines entered as file 0 followed by the
+    // loaded files, used to point errors at them
+    vm: Interpreter,
+    // how many instructions have been handed to the interpreter
+    loaded: usize,
+    // the compiler and linker before the current line or open block,
+    // restored when lowering fails so a bad line leaves no trace
+    checkpoint: Option<(CodeGen, Linker)>,
+}
+
+impl Default for Repl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Repl {
+    pub fn new() -> Self {
+        let mut vm = Interpreter::new();
+        vm.source_map = Some(SourceMap::new(SOURCE_NAME, "", Vec::new()));
+        Repl {
+            options: ParseOptions::default(),
+            max_steps: DEFAULT_MAX_STEPS,
+            compiler: CodeGen::new(),
+            linker: Linker::default(),
+            vm,
+            loaded: 0,
+            checkpoint: None,
+        }
+    }
+
+    /// Reads lines until the end of input, printing the stack after each
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: &mut W) -> io::Result<()> {
+        self.prompt(out)?;
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            let result = match line.split_once(' ').unwrap_or((line, "")) {
+                ("", _) => Ok(()),
+                (":vars", _) => self.print_vars(out),
+                (":stack", _) => writeln!(out, "{:?}", self.vm.stack),
+                (":reset", _) => {
+                    *self = Repl {
+                        options: self.options,
+                        max_steps: self.max_steps,
+                        ..Repl::new()
+                    };
+                    Ok(())
+                }
+                (":load", path) => {
+                    match self.load(path.trim()) {
+                        Ok(()) => writeln!(out, "{:?}", self.vm.stack)?,
+                        Err(err) => writeln!(out, "error: {}", err)?,
+                    }
+                    Ok(())
+                }
+                (command, _) if command.starts_with(':') => {
+                    writeln!(out, "unknown command {}", command)
+                }
+                _ => {
+                    match self.eval(line) {
+                        Ok(()) if self.compiler.in_block() => (),
+                        Ok(()) => writeln!(out, "{:?}", self.vm.stack)?,
+                        Err(err) => writeln!(out, "error: {}", err)?,
+                    }
+                    Ok(())
+                }
+            };
+            result?;
+            self.prompt(out)?;
+        }
+        writeln!(out)
+    }
+
+    fn prompt<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        if self.compiler.in_block() {
+            write!(out, "... ")?;
+        } else {
+            write!(out, "> ")?;
+        }
+        out.flush()
+    }
+
+    fn print_vars<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut variables: Vec<_> = self.compiler.variable_names().iter().collect();
+        variables.sort();
+        for (name, addr) in variables {
+            let val = self.vm.memory.load(*addr as i64).unwrap_or(0);
+            writeln!(out, "{} = {}", name, val)?;
+        }
+        Ok(())
+    }
+
+    /// Lowers and, unless it opens or continues a block, executes one line
+    pub fn eval(&mut self, line: &str) -> Result<(), ExecError> {
+        let stmt = parser::parse_statement_with(line, self.options)?;
+        let source_map = self.vm.source_map.get_or_insert_with(SourceMap::default);
+        let typed = &mut source_map.files[0].lines;
+        typed.push(line.to_string());
+        let line = typed.len();
+        self.start_lowering();
+        let lowered = self.compiler.lower_line(&stmt, line);
+        self.finish_lowering(lowered)
+    }
+
+    /// Lowers and executes every statement of a source file along with the
+    /// files it imports
+    pub fn load(&mut self, path: &str) -> Result<(), ExecError> {
+        self.start_lowering();
+        let files = &mut self
+            .vm
+            .source_map
+            .get_or_insert_with(SourceMap::default)
+            .files;
+        let lowered =
+            self.linker
+                .lower_file(&mut self.compiler, files, Path::new(path), self.options);
+        self.finish_lowering(lowered)
+    }
+
+    fn start_lowering(&mut self) {
+        if self.checkpoint.is_none() {
+            self.checkpoint = Some((self.compiler.clone(), self.linker.clone()));
+        }
+    }
+
+    // hands what was lowered to the interpreter and runs it once no block
+    // is left open, restoring the checkpoint if that fails
+    fn finish_lowering(&mut self, lowered: Result<(), ExecError>) -> Result<(), ExecError> {
+        let resolved = match lowered {
+            Ok(()) if self.compiler.in_block() => return Ok(()),
+            Ok(()) => self.compiler.resolve(),
+            Err(err) => Err(err),
+        };
+        let checkpoint = self.checkpoint.take();
+        let instructions = match resolved {
+            Ok(instructions) => instructions,
+            Err(err) => {
+                let (compiler, linker) = checkpoint.unwrap_or_default();
+                self.compiler = compiler;
+                self.linker = linker;
+                return Err(err);
+            }
+        };
+        self.vm.extend(&instructions[self.loaded..]);
+        let source_map = self.vm.source_map.get_or_insert_with(SourceMap::default);
+        let files = &self.compiler.instruction_files()[self.loaded..];
+        source_map.instruction_files.extend_from_slice(files);
+        let lines = &self.compiler.instruction_lines()[self.loaded..];
+        source_map.instruction_lines.extend_from_slice(lines);
+        self.loaded = instructions.len();
+        self.vm.jump_targets = Some(self.compiler.jump_targets());
+        self.execute()
+    }
+
+    // runs up to the end of everything lowered so far, a failed instruction
+    // is skipped so later lines do not run into it again
+    fn execute(&mut self) -> Result<(), ExecError> {
+        self.vm.fuel = Some(self.max_steps);
+        while !self.vm.is_finished() {
+            if let StepResult::Error(err) = self.vm.step() {
+                self.vm.curr_instruction_idx = self.loaded;
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn session(input: &str) -> String {
+        let mut out = Vec::new();
+        Repl::new().run(input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_persistent_state() {
+        let mut repl = Repl::new();
+        repl.eval("push 2").unwrap();
+        repl.eval("var x = 5").unwrap();
+        repl.eval("load x").unwrap();
+        repl.eval("add").unwrap();
+        assert_eq!(repl.vm.stack, vec![7]);
+    }
+
+    #[test]
+    fn test_blocks_wait_for_end() {
+        let out = session("func double(a)\nload a\nload a\nadd\nend\npush 4\ncall double\n");
+        assert!(out.contains("... "));
+        assert!(out.ends_with("[8]\n> \n"));
+    }
+
+    #[test]
+    fn test_errors_leave_no_trace() {
+        let mut repl = Repl::new();
+        repl.eval("push 1").unwrap();
+        assert!(matches!(
+            repl.eval("call nowhere"),
+            Err(ExecError::UndefinedLabel { line: 2, .. })
+        ));
+        assert!(repl.eval("add").is_err());
+        assert!(repl.eval("push 2").is_ok());
+        assert_eq!(repl.vm.stack, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_jump_targets_grow() {
+        let mut repl = Repl::new();
+        repl.eval("!![x] push 1").unwrap();
+        repl.eval("!![y] push 2").unwrap();
+        assert_eq!(repl.vm.jump_targets, Some(BTreeSet::from([0, 1])));
+    }
+
+    #[test]
+    fn test_endless_loop() {
+        let mut repl = Repl::new();
+        repl.max_steps = 1000;
+        assert!(matches!(
+            repl.eval("!![x] goto x"),
+            Err(ExecError::OutOfFuel(_))
+        ));
+        repl.eval("push 2").unwrap();
+        assert_eq!(repl.vm.stack, vec![2]);
+    }
+
+    #[test]
+    fn test_load_imports() {
+        let mut repl = Repl::new();
+        repl.load("tests/test_data/import-test.spd").unwrap();
+        assert_eq!(repl.vm.stack, vec![85]);
+        repl.eval("call util::double").unwrap();
+        assert_eq!(repl.vm.stack, vec![170]);
+    }
+
+    #[test]
+    fn test_error_locations() {
+        let mut repl = Repl::new();
+        repl.eval("push 1").unwrap();
+        repl.load("tests/test_data/call-test.spd").unwrap();
+        repl.eval("pop").unwrap();
+        let err = repl.eval("add").unwrap_err();
+        let location = err.snapshot().and_then(|at| at.location.clone()).unwrap();
+        assert_eq!((location.file.as_str(), location.line), (SOURCE_NAME, 3));
+        assert_eq!(location.text, "add");
+    }
+
+    #[test]
+    fn test_meta_commands() {
+        let out = session(
+            "var y = 3\n:vars\n:reset\n:vars\n:stack\n:load tests/test_data/call-test.spd\n",
+        );
+        assert!(out.contains("y = 3\n"));
+        assert!(out.contains("[50]"));
+    }
+}
@@ -2,28 +2,272 @@
 // Use of this source code is governed by the MIT License which can be
 // found in the LICENSE file.
 
+use std::collections::{BTreeMap, BTreeSet};
 use std::fmt;
-#[derive(Debug)]
-pub struct ExecError {
-    details: String,
+
+use crate::verify::Diagnostic;
+
+/// How many values from the top of the stack are kept in a Snapshot
+pub const STACK_SNAPSHOT_LEN: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExecError {
+    Io(String),
+    Parse(Box<ParseError>),
+    BadBytecode(String),
+    // the errors found while lowering name the imported file and note the
+    // macro a line was expanded from like ParseError does
+    UndefinedLabel {
+        file: Option<String>,
+        label: String,
+        line: usize,
+        note: Option<Box<str>>,
+    },
+    UndefinedVariable {
+        file: Option<String>,
+        name: String,
+        line: usize,
+        note: Option<Box<str>>,
+    },
+    BlockMismatch {
+        file: Option<String>,
+        line: usize,
+        details: String,
+        note: Option<Box<str>>,
+    },
+    // an import cycle or an import that cannot be resolved
+    Import(String),
+    // a malformed macro definition or a call that cannot be expanded
+    Macro {
+        line: usize,
+        details: String,
+    },
+    // every error the verifier found in a program that was asked to be
+    // verified before running
+    Verify(Vec<Diagnostic>),
+    StackUnderflow(Box<Snapshot>),
+    DivideByZero(Box<Snapshot>),
+    Overflow(Box<Snapshot>),
+    BadAddress {
+        address: i64,
+        at: Box<Snapshot>,
+    },
+    BadJumpTarget {
+        target: i64,
+        at: Box<Snapshot>,
+    },
+    // a jump that pops its destination landed inside the program but not
+    // on a label or the return address of a call
+    UnexpectedJumpTarget {
+        target: i64,
+        at: Box<Snapshot>,
+    },
+    CallDepthExceeded(Box<Snapshot>),
+    ReturnWithoutCall(Box<Snapshot>),
+    OutOfFuel(Box<Snapshot>),
 }
 
 impl ExecError {
-    pub fn new(details: &str) -> Self {
-        return ExecError {
-            details: details.to_string(),
-        };
+    /// The location information if the error came from the parser
+    pub fn parse_error(&self) -> Option<&ParseError> {
+        match self {
+            Self::Parse(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+
+    /// The state of the interpreter if this is a runtime error
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        match self {
+            Self::StackUnderflow(at)
+            | Self::DivideByZero(at)
+            | Self::Overflow(at)
+            | Self::BadAddress { at, .. }
+            | Self::BadJumpTarget { at, .. }
+            | Self::UnexpectedJumpTarget { at, .. }
+            | Self::CallDepthExceeded(at)
+            | Self::ReturnWithoutCall(at)
+            | Self::OutOfFuel(at) => Some(at.as_ref()),
+            Self::Io(_)
+            | Self::Parse(_)
+            | Self::BadBytecode(_)
+            | Self::UndefinedLabel { .. }
+            | Self::UndefinedVariable { .. }
+            | Self::BlockMismatch { .. }
+            | Self::Import(_)
+            | Self::Macro { .. }
+            | Self::Verify(_) => None,
+        }
     }
 }
 
 impl fmt::Display for ExecError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self.details)
+        match self {
+            Self::Io(details) => write!(f, "could not read file: {}", details),
+            Self::Parse(err) => write!(f, "parse error at {}", err),
+            Self::BadBytecode(details) => write!(f, "invalid bytecode: {}", details),
+            Self::UndefinedLabel {
+                file,
+                label,
+                line,
+                note,
+            } => {
+                write_line(f, file, *line)?;
+                write!(f, "could not find matching label to: {}", label)?;
+                write_note(f, note)
+            }
+            Self::UndefinedVariable {
+                file,
+                name,
+                line,
+                note,
+            } => {
+                write_line(f, file, *line)?;
+                write!(f, "uninitialized variable: {}", name)?;
+                write_note(f, note)
+            }
+            Self::BlockMismatch {
+                file,
+                line,
+                details,
+                note,
+            } => {
+                write_line(f, file, *line)?;
+                write!(f, "{}", details)?;
+                write_note(f, note)
+            }
+            Self::Import(details) => write!(f, "import failed: {}", details),
+            Self::Macro { line, details } => write!(f, "line {}: {}", line, details),
+            Self::Verify(diagnostics) => {
+                let diagnostics: Vec<String> =
+                    diagnostics.iter().map(ToString::to_string).collect();
+                write!(f, "verification failed: {}", diagnostics.join("; "))
+            }
+            Self::StackUnderflow(at) => write!(f, "stack empty at {}", at),
+            Self::DivideByZero(at) => write!(f, "division by zero at {}", at),
+            Self::Overflow(at) => write!(f, "arithmetic overflow at {}", at),
+            Self::BadAddress { address, at } => {
+                let reason = if *address < 0 {
+                    "negative"
+                } else {
+                    "out of range"
+                };
+                write!(f, "{} memory address {} at {}", reason, address, at)
+            }
+            Self::BadJumpTarget { target, at } => {
+                write!(f, "invalid jump target {} at {}", target, at)
+            }
+            Self::UnexpectedJumpTarget { target, at } => write!(
+                f,
+                "jump to {} which is not a label or return address at {}",
+                target, at
+            ),
+            Self::CallDepthExceeded(at) => write!(f, "maximum call depth exceeded at {}", at),
+            Self::ReturnWithoutCall(at) => write!(f, "return without a call at {}", at),
+            Self::OutOfFuel(at) => write!(f, "step limit reached at {}", at),
+        }
     }
 }
 
 impl std::error::Error for ExecError {}
 
+impl From<ParseError> for ExecError {
+    fn from(err: ParseError) -> Self {
+        Self::Parse(Box::new(err))
+    }
+}
+
+/// The state of the interpreter when it hit a runtime error. Instructions
+/// that fail leave the stack as it was before they ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub instruction_idx: usize,
+    // None once the program has run off the end of its instructions
+    pub instruction: Option<Instruction>,
+    // at most STACK_SNAPSHOT_LEN values with the top of the stack last
+    pub stack_top: Vec<i64>,
+    // the source line the instruction was lowered from if a source map
+    // was available
+    pub location: Option<SourceLocation>,
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.instruction {
+            Some(instruction) => write!(
+                f,
+                "instruction {} ({:?}) with stack top {:?}",
+                self.instruction_idx, instruction, self.stack_top
+            )?,
+            None => write!(
+                f,
+                "end of program ({}) with stack top {:?}",
+                self.instruction_idx, self.stack_top
+            )?,
+        }
+        match &self.location {
+            Some(location) => write!(f, " at {}", location),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A syntax error in a source file. Lines and columns start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    // the file the error is in when it is not the one being compiled
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    // what the parser was trying to match, innermost first
+    pub expected: Box<[String]>,
+    // the full source line the error occurred on
+    pub snippet: String,
+    // nom's trace of every parser that failed
+    pub trace: String,
+    // where the line came from when it was produced by a macro
+    pub note: Option<Box<str>>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: ", self.line, self.column)?;
+        match self.expected.first() {
+            Some(expected) => write!(f, "expected {}", expected)?,
+            None => write!(f, "invalid syntax")?,
+        }
+        write_note(f, &self.note)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// the line an error was found on, naming the file when it was imported
+fn write_line(f: &mut fmt::Formatter<'_>, file: &Option<String>, line: usize) -> fmt::Result {
+    match file {
+        Some(file) => write!(f, "line {} of {}: ", line, file),
+        None => write!(f, "line {}: ", line),
+    }
+}
+
+// where a line produced by a macro came from, after the error it caused
+fn write_note(f: &mut fmt::Formatter<'_>, note: &Option<Box<str>>) -> fmt::Result {
+    match note {
+        Some(note) => write!(f, " ({})", note),
+        None => Ok(()),
+    }
+}
+
+/// Where a jump goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    // an instruction index, codegen resolves every label to one
+    Addr(usize),
+    // popped off the stack before anything else the jump pops
+    Stack,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Instruction {
     Push(i64),
@@ -32,15 +276,173 @@ pub enum Instruction {
     // pops destination of stack then pops into dest
     Store,
     Pop,
-    Goto,
-    // pops sentinal off stack
-    GotoEqual,
+    Goto(Target),
+    // pops sentinal off stack and jumps if it is zero
+    GotoEqual(Target),
+    // the remaining conditional jumps pop the same operands as GotoEqual
+    // but compare the sentinal against zero with a different relation
+    GotoNotEqual(Target),
+    GotoLess(Target),
+    GotoGreater(Target),
+    GotoLessEqual(Target),
+    GotoGreaterEqual(Target),
     // Pushes a second copy of what is at the top of the stack
     RePush,
     ClearStack,
+    // pushes the address of the next instruction onto the call stack then
+    // jumps to the operand, the operand stack is shared with the callee
+    Call(usize),
+    // pops an address off the call stack and jumps to it, discarding the
+    // frame the callee entered if any
+    Ret,
+    // starts a function's frame with room for the given number of slots,
+    // the first operand many arguments are popped into the first slots
+    Enter(usize, usize),
+    // push or pop a slot of the current frame
+    LoadLocal(usize),
+    StoreLocal(usize),
+    // ends the program with the operand as its result, or with the top of
+    // the stack left in place like running off the end when there is none
+    Halt(Option<i64>),
     NoOp,
     Add,
     Sub,
     Mul,
     Div,
+    // remainder of the top of the stack divided by the second element
+    Mod,
+    // replaces the top of the stack with its negation
+    Neg,
+    // comparisons pop the top two elements of the stack and push 1 if
+    // `top <op> second` holds and 0 otherwise, mirroring the operand order
+    // of Sub
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+impl Instruction {
+    /// Where the instruction jumps if it is a goto or conditional goto
+    pub fn jump_target(&self) -> Option<Target> {
+        match self {
+            Self::Goto(target)
+            | Self::GotoEqual(target)
+            | Self::GotoNotEqual(target)
+            | Self::GotoLess(target)
+            | Self::GotoGreater(target)
+            | Self::GotoLessEqual(target)
+            | Self::GotoGreaterEqual(target) => Some(*target),
+            _ => None,
+        }
+    }
+
+    pub fn jump_target_mut(&mut self) -> Option<&mut Target> {
+        match self {
+            Self::Goto(target)
+            | Self::GotoEqual(target)
+            | Self::GotoNotEqual(target)
+            | Self::GotoLess(target)
+            | Self::GotoGreater(target)
+            | Self::GotoLessEqual(target)
+            | Self::GotoGreaterEqual(target) => Some(target),
+            _ => None,
+        }
+    }
+}
+
+/// A lowered program along with what is needed to relate it back to its
+/// source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+    pub debug: Option<DebugInfo>,
+    // every index the program was compiled to jump to, its labels and the
+    // instructions following a call. Jumps that pop their destination must
+    // land on one of them, None allows any index in the program.
+    pub jump_targets: Option<BTreeSet<usize>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    pub source_map: SourceMap,
+    // instruction index of every label
+    pub labels: BTreeMap<String, usize>,
+    // memory address of every global variable
+    pub variables: BTreeMap<String, usize>,
+    // the frame of every function keyed by the index of its Enter
+    pub frames: BTreeMap<usize, FrameLayout>,
+}
+
+/// The slots a function's Enter creates
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameLayout {
+    // one past the last instruction of the function
+    pub end: usize,
+    // the name of each parameter and local, by slot
+    pub slots: Vec<String>,
+}
+
+/// Maps every lowered instruction back to the source line it came from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    // the file being compiled first, followed by the files it imports
+    pub files: Vec<SourceFile>,
+    // index into files for each instruction
+    pub instruction_files: Vec<usize>,
+    // 1 based source line for each instruction
+    pub instruction_lines: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceFile {
+    pub name: String,
+    pub lines: Vec<String>,
+}
+
+impl SourceFile {
+    pub fn new(name: &str, source: &str) -> Self {
+        SourceFile {
+            name: name.to_string(),
+            lines: source.lines().map(str::to_string).collect(),
+        }
+    }
+}
+
+impl SourceMap {
+    /// A map for a program lowered from a single file
+    pub fn new(file: &str, source: &str, instruction_lines: Vec<usize>) -> Self {
+        SourceMap {
+            files: vec![SourceFile::new(file, source)],
+            instruction_files: vec![0; instruction_lines.len()],
+            instruction_lines,
+        }
+    }
+
+    pub fn lookup(&self, instruction_idx: usize) -> Option<SourceLocation> {
+        let file = self
+            .files
+            .get(*self.instruction_files.get(instruction_idx)?)?;
+        let line = *self.instruction_lines.get(instruction_idx)?;
+        Some(SourceLocation {
+            file: file.name.clone(),
+            line,
+            text: file.lines.get(line.checked_sub(1)?)?.trim().to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    pub text: String,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.text)
+    }
 }
@@ -0,0 +1,470 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+//! Checks how deep the stack is at every instruction of a lowered program
+//! without running it.
+//!
+//! Jumps are followed when their target is an address, which is how codegen
+//! lowers every goto. Each function is checked on its own, starting from an
+//! empty stack that it may dig below into its caller's values, and
+//! summarized by how many of those it needs and how many values it leaves
+//! behind so calls can be checked like any other instruction.
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::types::{Instruction, Instruction::*, Program, SourceLocation, Target};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    // equal to the program length for problems at its end
+    pub instruction_idx: usize,
+    pub problem: Problem,
+    pub location: Option<SourceLocation>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    // an instruction or call needs more values than the stack holds
+    Underflow { needed: usize, depth: usize },
+    // paths reach the same instruction, or the returns of a function, with
+    // different stack depths
+    DepthMismatch { expected: i64, found: i64 },
+    // the program can finish with nothing on the stack to return
+    NoResult,
+    BadJumpTarget(usize),
+    // a jump whose destination is computed at runtime, which is not
+    // followed
+    ComputedJump,
+    // this many instructions starting here can never execute
+    Unreachable(usize),
+}
+
+impl Diagnostic {
+    /// Whether the program is certain to misbehave, rather than just
+    /// holding something suspicious
+    pub fn is_error(&self) -> bool {
+        !matches!(
+            self.problem,
+            Problem::ComputedJump | Problem::Unreachable(_)
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{} at {}", self.problem, location),
+            None => write!(
+                f,
+                "{} at instruction {}",
+                self.problem, self.instruction_idx
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Underflow { needed, depth } => write!(
+                f,
+                "stack underflow, {} values needed but the stack holds {}",
+                needed, depth
+            ),
+            Self::DepthMismatch { expected, found } => write!(
+                f,
+                "stack depth {} where another path has depth {}",
+                found, expected
+            ),
+            Self::NoResult => write!(f, "program can finish with an empty stack"),
+            Self::BadJumpTarget(target) => write!(f, "invalid jump target {}", target),
+            Self::ComputedJump => write!(f, "jump target computed at runtime is not checked"),
+            Self::Unreachable(count) => write!(f, "{} unreachable instructions", count),
+        }
+    }
+}
+
+/// Verifies a program, pointing every diagnostic at its source line when
+/// the program has debug info
+pub fn verify_program(program: &Program) -> Vec<Diagnostic> {
+    let mut diagnostics = verify(&program.instructions);
+    if let Some(debug) = &program.debug {
+        for diagnostic in diagnostics.iter_mut() {
+            diagnostic.location = debug.source_map.lookup(diagnostic.instruction_idx);
+        }
+    }
+    diagnostics
+}
+
+/// Every problem found in the program, ordered by instruction
+pub fn verify(instructions: &[Instruction]) -> Vec<Diagnostic> {
+    let mut verifier = Verifier {
+        instructions,
+        reached: vec![false; instructions.len()],
+        summaries: HashMap::new(),
+        diagnostics: Vec::new(),
+        computed_jumps: false,
+    };
+    verifier.walk(0, false);
+    // unreachable code is only reported when every jump could be followed
+    if !verifier.computed_jumps {
+        let mut idx = 0;
+        while idx < instructions.len() {
+            let count = verifier.reached[idx..]
+                .iter()
+                .take_while(|reached| !**reached)
+                .count();
+            if count > 0 {
+                verifier.report(idx, Problem::Unreachable(count));
+            }
+            idx += count.max(1);
+        }
+    }
+    let mut diagnostics = verifier.diagnostics;
+    diagnostics.sort_by_key(|diagnostic| diagnostic.instruction_idx);
+    diagnostics
+}
+
+// what a call to a function does to its caller's stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Summary {
+    // how many of the caller's values it pops
+    needs: usize,
+    // how much deeper the stack is once it returns, None if it never does
+    net: Option<i64>,
+}
+
+// the outcome of walking one function or the top level
+struct Walk {
+    // the lowest depth reached, below zero when the caller's values are used
+    min_depth: i64,
+    return_depth: Option<i64>,
+    // a recursive call whose summary was not known yet cut a path short
+    incomplete: bool,
+}
+
+enum SummaryState {
+    InProgress(Option<Summary>),
+    Done(Summary),
+}
+
+struct Verifier<'a> {
+    instructions: &'a [Instruction],
+    reached: Vec<bool>,
+    // keyed by the address functions are called at
+    summaries: HashMap<usize, SummaryState>,
+    diagnostics: Vec<Diagnostic>,
+    computed_jumps: bool,
+}
+
+impl Verifier<'_> {
+    fn report(&mut self, instruction_idx: usize, problem: Problem) {
+        let diagnostic = Diagnostic {
+            instruction_idx,
+            problem,
+            location: None,
+        };
+        // recursive functions are walked more than once
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    // walks every path from entry with depths relative to the stack at
+    // entry, which may only go below zero inside a function
+    fn walk(&mut self, entry: usize, in_function: bool) -> Walk {
+        let len = self.instructions.len();
+        let mut depths: Vec<Option<i64>> = vec![None; len];
+        let mut walk = Walk {
+            min_depth: 0,
+            return_depth: None,
+            incomplete: false,
+        };
+        let mut pending = vec![(entry, 0)];
+        while let Some((idx, depth)) = pending.pop() {
+            if idx >= len {
+                if !in_function && depth < 1 {
+                    self.report(len, Problem::NoResult);
+                }
+                continue;
+            }
+            match depths[idx] {
+                Some(expected) if expected != depth => {
+                    self.report(
+                        idx,
+                        Problem::DepthMismatch {
+                            expected,
+                            found: depth,
+                        },
+                    );
+                    continue;
+                }
+                Some(_) => continue,
+                None => depths[idx] = Some(depth),
+            }
+            self.reached[idx] = true;
+            let instruction = &self.instructions[idx];
+            let (pops, pushes) = match instruction {
+                Call(addr) => match self.call(idx, *addr) {
+                    Some(Summary {
+                        needs,
+                        net: Some(net),
+                    }) => (needs as i64, needs as i64 + net),
+                    Some(Summary { needs, net: None }) => {
+                        // check what it needs even though it never returns
+                        self.pop(idx, depth, needs as i64, in_function, &mut walk);
+                        continue;
+                    }
+                    None => {
+                        walk.incomplete = true;
+                        continue;
+                    }
+                },
+                instruction => stack_effect(instruction),
+            };
+            if !self.pop(idx, depth, pops, in_function, &mut walk) {
+                continue;
+            }
+            let next_depth = match instruction {
+                // inside a function this drops its caller's values too,
+                // which is not tracked
+                ClearStack => 0,
+                _ => depth - pops + pushes,
+            };
+            match instruction {
+                Goto(target) => {
+                    if let Some(target) = self.jump_target(idx, *target) {
+                        pending.push((target, next_depth));
+                    }
+                }
+                Ret if in_function => match walk.return_depth {
+                    Some(expected) if expected != next_depth => self.report(
+                        idx,
+                        Problem::DepthMismatch {
+                            expected,
+                            found: next_depth,
+                        },
+                    ),
+                    Some(_) => (),
+                    None => walk.return_depth = Some(next_depth),
+                },
+                // a return outside of a call fails at runtime
+                Ret => (),
+                Halt(_) => (),
+                _ => {
+                    let target = instruction.jump_target();
+                    if let Some(target) = target.and_then(|target| self.jump_target(idx, target)) {
+                        pending.push((target, next_depth));
+                    }
+                    pending.push((idx + 1, next_depth));
+                }
+            }
+        }
+        walk
+    }
+
+    // checks the stack holds enough values to pop, returning whether the
+    // path can continue
+    fn pop(
+        &mut self,
+        idx: usize,
+        depth: i64,
+        pops: i64,
+        in_function: bool,
+        walk: &mut Walk,
+    ) -> bool {
+        if depth - pops >= 0 {
+            return true;
+        }
+        if in_function {
+            walk.min_depth = walk.min_depth.min(depth - pops);
+            return true;
+        }
+        self.report(
+            idx,
+            Problem::Underflow {
+                needed: pops as usize,
+                depth: depth as usize,
+            },
+        );
+        false
+    }
+
+    // where a jump goes if that is known before running
+    fn jump_target(&mut self, idx: usize, target: Target) -> Option<usize> {
+        match target {
+            Target::Addr(addr) if addr > self.instructions.len() => {
+                self.report(idx, Problem::BadJumpTarget(addr));
+                None
+            }
+            Target::Addr(addr) => Some(addr),
+            Target::Stack => {
+                self.computed_jumps = true;
+                self.report(idx, Problem::ComputedJump);
+                None
+            }
+        }
+    }
+
+    // the summary of the function called at addr, None while it is still
+    // being walked by a recursive call
+    fn call(&mut self, idx: usize, addr: usize) -> Option<Summary> {
+        if addr > self.instructions.len() {
+            self.report(idx, Problem::BadJumpTarget(addr));
+            return Some(Summary {
+                needs: 0,
+                net: None,
+            });
+        }
+        match self.summaries.get(&addr) {
+            Some(SummaryState::Done(summary)) => return Some(*summary),
+            Some(SummaryState::InProgress(summary)) => return *summary,
+            None => (),
+        }
+        self.summaries.insert(addr, SummaryState::InProgress(None));
+        let mut walk = self.walk(addr, true);
+        // walk again with what the paths that did return tell us about the
+        // recursive calls
+        if walk.incomplete && walk.return_depth.is_some() {
+            let summary = summarize(&walk);
+            self.summaries
+                .insert(addr, SummaryState::InProgress(Some(summary)));
+            walk = self.walk(addr, true);
+        }
+        let summary = summarize(&walk);
+        self.summaries.insert(addr, SummaryState::Done(summary));
+        Some(summary)
+    }
+}
+
+fn summarize(walk: &Walk) -> Summary {
+    Summary {
+        needs: (-walk.min_depth) as usize,
+        net: walk.return_depth,
+    }
+}
+
+// how many values an instruction pops and then pushes, calls and ClearStack
+// are handled by the walk
+fn stack_effect(instruction: &Instruction) -> (i64, i64) {
+    match instruction {
+        Push(_) | LoadLocal(_) => (0, 1),
+        Load | Neg => (1, 1),
+        Store => (2, 0),
+        Goto(Target::Addr(_)) => (0, 0),
+        Pop | Goto(Target::Stack) | StoreLocal(_) => (1, 0),
+        GotoEqual(target)
+        | GotoNotEqual(target)
+        | GotoLess(target)
+        | GotoGreater(target)
+        | GotoLessEqual(target)
+        | GotoGreaterEqual(target) => match target {
+            Target::Addr(_) => (1, 0),
+            Target::Stack => (2, 0),
+        },
+        RePush => (1, 2),
+        // without a code the top of the stack is the result
+        Halt(None) => (1, 1),
+        Enter(params, _) => (*params as i64, 0),
+        Add | Sub | Mul | Div | Mod | Eq | Ne | Lt | Gt | Le | Ge => (2, 1),
+        Call(_) | Ret | ClearStack | Halt(Some(_)) | NoOp => (0, 0),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codegen::CodeGen;
+    use crate::parser;
+
+    fn verify_source(source: &str) -> Vec<Problem> {
+        let code = parser::parse(source).unwrap();
+        let instructions = CodeGen::new().lower_IR(&code).unwrap();
+        verify(&instructions)
+            .into_iter()
+            .map(|diagnostic| diagnostic.problem)
+            .collect()
+    }
+
+    #[test]
+    fn test_clean_programs() {
+        assert_eq!(verify_source("push 1\npush 2\nadd"), vec![]);
+        let source = "var n = 3\npush 0\nwhile n > 0\npush 1\nadd\nvar n = n - 1\nend";
+        assert_eq!(verify_source(source), vec![]);
+        let source = "func fact(n)\nif n <= 1\npush 1\nelse\nload n\npush 1\nload n\nsub\ncall fact\nmul\nend\nend\npush 5\ncall fact";
+        assert_eq!(verify_source(source), vec![]);
+    }
+
+    #[test]
+    fn test_underflow() {
+        let problems = verify_source("push 1\nadd");
+        assert_eq!(
+            problems,
+            vec![Problem::Underflow {
+                needed: 2,
+                depth: 1
+            }]
+        );
+        assert_eq!(verify_source("push 1\npop"), vec![Problem::NoResult]);
+        let problems = verify_source("func f(a, b)\nload a\nend\npush 1\ncall f");
+        assert_eq!(
+            problems[0],
+            Problem::Underflow {
+                needed: 2,
+                depth: 1
+            }
+        );
+    }
+
+    #[test]
+    fn test_depth_mismatch() {
+        let problems = verify_source("push 1\nif 1\npush 2\nend\nadd");
+        assert!(
+            problems.contains(&Problem::DepthMismatch {
+                expected: 2,
+                found: 1
+            }),
+            "{:?}",
+            problems
+        );
+        let instructions = vec![Push(0), GotoEqual(Target::Addr(3)), Push(5), NoOp];
+        let diagnostics = verify(&instructions);
+        assert_eq!(diagnostics[0].instruction_idx, 3);
+        assert_eq!(
+            diagnostics[0].problem,
+            Problem::DepthMismatch {
+                expected: 1,
+                found: 0
+            }
+        );
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let problems = verify_source("push 1\n!![a] goto a\npush 2\npush 3");
+        assert_eq!(problems, vec![Problem::Unreachable(2)]);
+        let instructions = vec![Push(1), Push(2), Add, Goto(Target::Stack), Push(3)];
+        let problems: Vec<_> = verify(&instructions)
+            .into_iter()
+            .map(|diagnostic| diagnostic.problem)
+            .collect();
+        assert_eq!(problems, vec![Problem::ComputedJump]);
+    }
+
+    #[test]
+    fn test_halt() {
+        assert_eq!(verify_source("halt 1"), vec![]);
+        assert_eq!(
+            verify_source("push 1\nhalt\npop"),
+            vec![Problem::Unreachable(1)]
+        );
+        assert_eq!(
+            verify_source("halt"),
+            vec![Problem::Underflow {
+                needed: 1,
+                depth: 0
+            }]
+        );
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+use std::fs;
+use std::path::PathBuf;
+
+use spyder::{bytecode, Options};
+
+mod common;
+
+use common::{test_programs, RUN_OPTIONS};
+
+#[test]
+fn test_round_trip() {
+    for path in test_programs() {
+        let program =
+            spyder::compile_file(&path, &RUN_OPTIONS).expect("test program should compile");
+        let bytes = bytecode::encode(&program).expect("encoding failed");
+        let decoded = bytecode::decode(&bytes).expect("decoding failed");
+        assert_eq!(decoded, program, "{}", path.display());
+        assert_eq!(
+            spyder::run_program(decoded, &RUN_OPTIONS),
+            spyder::run_program(program, &RUN_OPTIONS),
+            "{}",
+            path.display()
+        );
+    }
+}
+
+#[test]
+fn test_round_trip_stripped() {
+    for path in test_programs() {
+        let mut program =
+            spyder::compile_file(&path, &RUN_OPTIONS).expect("test program should compile");
+        program.debug = None;
+        let bytes = bytecode::encode(&program).expect("encoding failed");
+        assert_eq!(bytecode::decode(&bytes), Ok(program), "{}", path.display());
+    }
+}
+
+#[test]
+fn test_run_compiled_file() {
+    let program = spyder::compile_file(
+        &PathBuf::from("tests/test_data/call-test.spd"),
+        &Options::default(),
+    )
+    .expect("test program should compile");
+    let path = std::env::temp_dir().join(format!("spyder-call-test-{}.spdc", std::process::id()));
+    fs::write(&path, bytecode::encode(&program).expect("encoding failed")).unwrap();
+    let calculated = spyder::run_file(&path, &Options::default());
+    fs::remove_file(&path).unwrap();
+    assert_eq!(calculated, Ok(50));
+}
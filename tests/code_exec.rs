@@ -4,12 +4,14 @@
 
 use std::path::PathBuf;
 
-use spyder;
+use spyder::parser::ParseOptions;
+use spyder::types::ExecError;
+use spyder::Options;
 
 #[test]
 fn test_multiplication() {
     let path = PathBuf::from("tests/test_data/multiplication-test.spd");
-    let calculated = spyder::run_file(&path, false);
+    let calculated = spyder::run_file(&path, &Options::default());
     assert!(calculated.is_ok());
     assert_eq!(calculated.expect("error"), 21)
 }
@@ -17,7 +19,7 @@ fn test_multiplication() {
 #[test]
 fn test_calling() {
     let path = PathBuf::from("tests/test_data/call-test.spd");
-    let calculated = spyder::run_file(&path, false);
+    let calculated = spyder::run_file(&path, &Options::default());
     assert!(calculated.is_ok());
     assert_eq!(calculated.expect("error"), 50)
 }
@@ -25,6 +27,187 @@ fn test_calling() {
 #[test]
 fn test_variables() {
     let path = PathBuf::from("tests/test_data/variables-test.spd");
-    let calculated = spyder::run_file(&path, false);
+    let calculated = spyder::run_file(&path, &Options::default());
     assert_eq!(calculated.expect("error"), 21)
 }
+
+#[test]
+fn test_multiplication_allow_negatives() {
+    let path = PathBuf::from("tests/test_data/multiplication-allow-negatives.spd");
+    let options = Options {
+        parse: ParseOptions { ignore_case: true },
+        ..Options::default()
+    };
+    let calculated = spyder::run_file(&path, &options);
+    assert_eq!(calculated.expect("error"), 21)
+}
+
+#[test]
+fn test_comparison() {
+    let path = PathBuf::from("tests/test_data/comparison-test.spd");
+    let calculated = spyder::run_file(&path, &Options::default());
+    assert_eq!(calculated.expect("error"), 9)
+}
+
+#[test]
+fn test_error_source_location() {
+    let path = PathBuf::from("tests/test_data/stack-underflow.spd");
+    let err = spyder::run_file(&path, &Options::default()).expect_err("add should underflow");
+    let location = err.snapshot().and_then(|at| at.location.clone());
+    let location = location.expect("missing source location");
+    assert_eq!(location.line, 4);
+    assert_eq!(location.text, "add");
+}
+
+#[test]
+fn test_verify() {
+    for name in [
+        "control-flow-test",
+        "func-test",
+        "import-test",
+        "macro-test",
+    ] {
+        let path = PathBuf::from(format!("tests/test_data/{}.spd", name));
+        let program = spyder::compile_file(&path, &Options::default()).expect("error");
+        assert_eq!(spyder::verify::verify_program(&program), vec![], "{}", name);
+    }
+    let path = PathBuf::from("tests/test_data/stack-underflow.spd");
+    let options = Options {
+        verify: true,
+        ..Options::default()
+    };
+    let err = spyder::run_file(&path, &options).expect_err("add should underflow");
+    let diagnostics = match err {
+        ExecError::Verify(diagnostics) => diagnostics,
+        err => panic!("unexpected error {:?}", err),
+    };
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].location.as_ref().map(|at| at.line), Some(4));
+}
+
+#[test]
+fn test_call_arguments() {
+    let path = PathBuf::from("tests/test_data/call-args-test.spd");
+    let calculated = spyder::run_file(&path, &Options::default());
+    assert_eq!(calculated.expect("error"), 85)
+}
+
+#[test]
+fn test_functions() {
+    let path = PathBuf::from("tests/test_data/func-test.spd");
+    let calculated = spyder::run_file(&path, &Options::default());
+    assert_eq!(calculated.expect("error"), 155)
+}
+
+#[test]
+fn test_expressions() {
+    let path = PathBuf::from("tests/test_data/expression-test.spd");
+    let calculated = spyder::run_file(&path, &Options::default());
+    assert_eq!(calculated.expect("error"), 11)
+}
+
+#[test]
+fn test_control_flow() {
+    let path = PathBuf::from("tests/test_data/control-flow-test.spd");
+    let calculated = spyder::run_file(&path, &Options::default());
+    assert_eq!(calculated.expect("error"), 40)
+}
+
+#[test]
+fn test_macros() {
+    let path = PathBuf::from("tests/test_data/macro-test.spd");
+    let calculated = spyder::run_file(&path, &Options::default());
+    assert_eq!(calculated.expect("error"), 50)
+}
+
+#[test]
+fn test_halt() {
+    let path = PathBuf::from("tests/test_data/halt-test.spd");
+    let calculated = spyder::run_file(&path, &Options::default());
+    assert_eq!(calculated.expect("error"), 6)
+}
+
+#[test]
+fn test_imports() {
+    let path = PathBuf::from("tests/test_data/import-test.spd");
+    let calculated = spyder::run_file(&path, &Options::default());
+    assert_eq!(calculated.expect("error"), 85)
+}
+
+#[test]
+fn test_import_cycle() {
+    let path = PathBuf::from("tests/test_data/cycle/a.spd");
+    let err = spyder::run_file(&path, &Options::default()).expect_err("imports form a cycle");
+    assert!(matches!(err, ExecError::Import(_)), "{}", err);
+}
+
+#[test]
+fn test_namespace_clash() {
+    let path = PathBuf::from("tests/test_data/namespace-clash/main.spd");
+    let err = spyder::run_file(&path, &Options::default()).expect_err("both imports are named m");
+    assert!(matches!(err, ExecError::Import(_)), "{}", err);
+}
+
+#[test]
+fn test_macro_lowering_errors() {
+    for (file, line) in [
+        ("missing-end", "if 1"),
+        ("undefined-label", "goto nowhere"),
+        ("undefined-variable", "load zz"),
+    ] {
+        let path = PathBuf::from(format!("tests/test_data/macro-errors/{}.spd", file));
+        let err = spyder::run_file(&path, &Options::default()).expect_err(file);
+        let message = err.to_string();
+        assert!(message.starts_with("line 5: "), "{}", message);
+        assert!(
+            message.ends_with(&format!("(in macro m at line 2: {})", line)),
+            "{}",
+            message
+        );
+    }
+}
+
+#[test]
+fn test_error_in_import() {
+    let path = PathBuf::from("tests/test_data/undefined-in-import/main.spd");
+    let err = spyder::run_file(&path, &Options::default()).expect_err("nowhere is undefined");
+    match &err {
+        ExecError::UndefinedLabel {
+            file: Some(file),
+            line: 3,
+            ..
+        } => assert!(file.ends_with("broken.spd")),
+        err => panic!("unexpected error {}", err),
+    }
+    assert!(err.to_string().starts_with("line 3 of "), "{}", err);
+}
+
+#[test]
+fn test_imported_source_location() {
+    let path = PathBuf::from("tests/test_data/import-test.spd");
+    let program = spyder::compile_file(&path, &Options::default()).expect("error");
+    let debug = program.debug.expect("missing debug info");
+    let enter = debug.labels["util::double"];
+    let location = debug
+        .source_map
+        .lookup(enter)
+        .expect("missing source location");
+    assert!(location.file.ends_with("util.spd"));
+    assert_eq!(location.line, 1);
+}
+
+#[test]
+fn test_max_steps() {
+    let path = PathBuf::from("tests/test_data/infinite-loop.spd");
+    let err = spyder::run_file(
+        &path,
+        &Options {
+            max_steps: Some(1000),
+            ..Options::default()
+        },
+    )
+    .expect_err("loop should not finish");
+    assert!(matches!(err, ExecError::OutOfFuel(_)));
+    let location = err.snapshot().and_then(|at| at.location.clone());
+    assert!(location.is_some());
+}
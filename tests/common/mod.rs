@@ -0,0 +1,30 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+//! Fixtures shared by the tests that run every program in tests/test_data.
+use std::fs;
+use std::path::PathBuf;
+
+use spyder::parser::ParseOptions;
+use spyder::Options;
+
+// keeps programs that never finish from hanging the tests, some of the
+// programs are written with capitalized mnemonics
+pub const RUN_OPTIONS: Options = Options {
+    debug: false,
+    max_steps: Some(100_000),
+    verify: false,
+    optimize: false,
+    parse: ParseOptions { ignore_case: true },
+};
+
+pub fn test_programs() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir("tests/test_data")
+        .expect("missing test data")
+        .map(|entry| entry.expect("unreadable test data").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "spd"))
+        .collect();
+    paths.sort();
+    paths
+}
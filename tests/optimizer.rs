@@ -0,0 +1,55 @@
+// Copyright 2021 The Spyder Authors.
+// Use of this source code is governed by the MIT License which can be
+// found in the LICENSE file.
+
+use std::path::PathBuf;
+
+use spyder::Options;
+
+mod common;
+
+use common::{test_programs, RUN_OPTIONS};
+
+#[test]
+fn test_same_results() {
+    let optimized_options = Options {
+        optimize: true,
+        ..RUN_OPTIONS
+    };
+    for path in test_programs() {
+        let program =
+            spyder::compile_file(&path, &RUN_OPTIONS).expect("test program should compile");
+        let optimized =
+            spyder::compile_file(&path, &optimized_options).expect("test program should compile");
+        assert!(
+            optimized.instructions.len() <= program.instructions.len(),
+            "{}",
+            path.display()
+        );
+        let result = spyder::run_program(program, &RUN_OPTIONS);
+        let optimized_result = spyder::run_program(optimized, &RUN_OPTIONS);
+        // errors point at different instructions once the program shrinks
+        match (result, optimized_result) {
+            (Ok(val), optimized) => assert_eq!(optimized, Ok(val), "{}", path.display()),
+            (Err(err), optimized) => assert_eq!(
+                std::mem::discriminant(&optimized.expect_err("optimized program should fail")),
+                std::mem::discriminant(&err),
+                "{}",
+                path.display()
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_optimized_program_shrinks() {
+    let path = PathBuf::from("tests/test_data/expression-test.spd");
+    let options = Options {
+        optimize: true,
+        ..Options::default()
+    };
+    let program = spyder::compile_file(&path, &Options::default()).expect("error");
+    let optimized = spyder::compile_file(&path, &options).expect("error");
+    assert!(optimized.instructions.len() < program.instructions.len());
+    assert_eq!(spyder::run_program(optimized, &Options::default()), Ok(11));
+}